@@ -1,18 +1,29 @@
+use crate::secret::Secret;
+use crate::video::{Clip, Rendition};
+
 use rand::{seq::SliceRandom, thread_rng};
-use reqwest::Client as ReqwestClient;
+use reqwest::{Client as ReqwestClient, Response, StatusCode};
 use serde::{
     ser::{Serialize, SerializeStruct, Serializer},
     Deserialize, Serialize as SerializeDerive,
 };
 use std::error::Error;
 use std::fmt;
-use std::time::Instant;
-use tracing::debug;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex as StdMutex, Weak,
+};
+use std::time::{Duration, Instant};
+use tokio::{sync::Mutex, time};
+use tracing::{debug, warn};
 
-const TOKEN_URL: &str = "https://api.gfycat.com/v1/oauth/token";
+const API_URL: &str = "https://api.gfycat.com/v1";
+/// How long before expiring the token is refreshed in the background
+const REFRESH_AHEAD: Duration = Duration::from_secs(60);
+/// Shortest wait between background refreshes, shorter lived tokens are refreshed when used
+const MIN_REFRESH_WAIT: Duration = Duration::from_secs(30);
 
-#[derive(SerializeDerive, PartialEq)]
-#[allow(dead_code)]
+#[derive(SerializeDerive, PartialEq, Debug)]
 pub enum GrantType {
     #[serde(rename = "password")]
     Password,
@@ -40,48 +51,86 @@ impl fmt::Display for GfycatErrorMessage {
     }
 }
 
+/// Error response from the API
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// `None` if the body wasn't a Gfycat error
+    pub error: Option<GfycatError>,
+}
+
+impl ApiError {
+    async fn read(response: Response) -> Self {
+        let status = response.status();
+        let error = match response.text().await {
+            Ok(body) => serde_json::from_str(&body).ok(),
+            Err(_) => None,
+        };
+
+        ApiError { status, error }
+    }
+
+    /// Whether the credentials or the token were rejected, as opposed to rate limits
+    /// and other failures that asking for a new token wouldn't fix
+    fn is_auth(&self) -> bool {
+        let invalid_grant = self.error.as_ref().is_some_and(|error| {
+            error
+                .errorMessage
+                .code
+                .replace('_', "")
+                .eq_ignore_ascii_case("invalidgrant")
+        });
+
+        matches!(
+            self.status,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) || invalid_grant
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.error {
+            Some(error) => write!(f, "{} ({})", error.errorMessage, self.status),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RequestError {
-    Gfycat(GfycatError),
-    Reqwest(reqwest::Error),
+    /// The credentials or the access token were rejected
+    Auth(ApiError),
+    /// Any other error response
+    Api(ApiError),
+    /// The request didn't go through or the response couldn't be read
+    Transport(reqwest::Error),
 }
 
 impl Error for RequestError {}
 
 impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let error = match self {
-            RequestError::Gfycat(error) => error.errorMessage.to_string(),
-            RequestError::Reqwest(error) => error.to_string(),
-        };
-        write!(f, "{}", error)
+        match self {
+            RequestError::Auth(error) => write!(f, "Gfycat authentication failed: {}", error),
+            RequestError::Api(error) => write!(f, "Gfycat request failed: {}", error),
+            RequestError::Transport(error) => write!(f, "{}", error),
+        }
     }
 }
 
 impl From<reqwest::Error> for RequestError {
     fn from(error: reqwest::Error) -> Self {
-        Self::Reqwest(error)
+        Self::Transport(error)
     }
 }
 
-/*impl From<RequestError> for CommandError {
-    fn from(error: RequestError) -> Self {
-        match error {
-            RequestError::Gfycat(error) => {
-                error.errorMessage
-            },
-            RequestError::Reqwest(error) => {
-                error.into()
-            }
-        }
-    }
-}*/
-
+#[derive(Debug)]
 pub struct TokenData {
     client_id: String,
-    client_secret: String,
+    client_secret: Secret<String>,
     username: Option<String>,
-    password: Option<String>,
+    password: Option<Secret<String>>,
     grant_type: GrantType,
 }
 
@@ -97,10 +146,10 @@ impl Serialize for TokenData {
         }?;
 
         state.serialize_field("client_id", &self.client_id)?;
-        state.serialize_field("client_secret", &self.client_secret)?;
+        state.serialize_field("client_secret", self.client_secret.expose())?;
         if self.grant_type == GrantType::Password {
             state.serialize_field("username", &self.username)?;
-            state.serialize_field("password", &self.password)?;
+            state.serialize_field("password", &self.password.as_ref().map(Secret::expose))?;
         }
         state.serialize_field("grant_type", &self.grant_type)?;
         state.end()
@@ -130,157 +179,322 @@ impl fmt::Display for ClientBuilderError {
     }
 }
 
+/// How the client gets its token, and which album endpoint that gives access to
+enum Grant {
+    /// Logs in as the album owner, so the album is read from their account
+    Password {
+        username: String,
+        password: Secret<String>,
+    },
+    /// Only the app is known, so the album is read from the public albums of its owner
+    ClientCredentials { album_owner: String },
+}
+
 pub struct ClientBuilder {
     client_id: String,
-    client_secret: String,
-    username: Option<String>,
-    password: Option<String>,
-    grant_type: Option<GrantType>,
+    client_secret: Secret<String>,
+    grant: Option<Grant>,
     album_id: String,
+    base_url: String,
 }
 
 impl ClientBuilder {
-    pub fn new(client_id: String, client_secret: String, album_id: String) -> Self {
+    pub fn new(client_id: String, client_secret: Secret<String>, album_id: String) -> Self {
         Self {
             client_id,
             client_secret,
-            username: Option::default(),
-            password: Option::default(),
-            grant_type: Option::default(),
+            grant: None,
             album_id,
+            base_url: API_URL.to_owned(),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn client_credentials_grant(mut self) -> Self {
-        self.username = None;
-        self.password = None;
-        self.grant_type = Some(GrantType::ClientCredentials);
+    /// Sends the requests somewhere else than the Gfycat API, e.g. a proxy or a mock server
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_owned();
         self
     }
 
-    pub fn password_grant(mut self, username: String, password: String) -> Self {
-        self.username = Some(username);
-        self.password = Some(password);
-        self.grant_type = Some(GrantType::Password);
+    /// Uses only the client id and secret, the album has to be public
+    pub fn client_credentials_grant(mut self, album_owner: String) -> Self {
+        self.grant = Some(Grant::ClientCredentials { album_owner });
         self
     }
 
-    pub fn build(self) -> Result<Client, ClientBuilderError> {
-        if self.grant_type.is_none() {
-            return Err(ClientBuilderError::GrantMissingError);
-        }
+    pub fn password_grant(mut self, username: String, password: Secret<String>) -> Self {
+        self.grant = Some(Grant::Password { username, password });
+        self
+    }
 
-        let grant_type = self.grant_type.unwrap();
-
-        let token_data = match grant_type {
-            GrantType::Password => TokenData {
-                client_id: self.client_id,
-                client_secret: self.client_secret,
-                username: self.username,
-                password: self.password,
-                grant_type,
-            },
-            GrantType::ClientCredentials => TokenData {
-                client_id: self.client_id,
-                client_secret: self.client_secret,
-                username: None,
-                password: None,
-                grant_type,
-            },
-            _ => panic!("Unknown grant type"),
+    pub fn build(self) -> Result<Client, ClientBuilderError> {
+        let grant = self.grant.ok_or(ClientBuilderError::GrantMissingError)?;
+
+        let (token_data, album_url) = match grant {
+            Grant::Password { username, password } => (
+                TokenData {
+                    client_id: self.client_id,
+                    client_secret: self.client_secret,
+                    username: Some(username),
+                    password: Some(password),
+                    grant_type: GrantType::Password,
+                },
+                format!("{}/me/albums/{}", self.base_url, self.album_id),
+            ),
+            Grant::ClientCredentials { album_owner } => (
+                TokenData {
+                    client_id: self.client_id,
+                    client_secret: self.client_secret,
+                    username: None,
+                    password: None,
+                    grant_type: GrantType::ClientCredentials,
+                },
+                format!(
+                    "{}/users/{}/albums/{}",
+                    self.base_url, album_owner, self.album_id
+                ),
+            ),
         };
 
+        let client = ReqwestClient::new();
         Ok(Client {
-            token: None,
-            token_data,
-            client: ReqwestClient::new(),
-            album_id: self.album_id,
-            gfycats: None,
-            time_since_last_request: None,
+            tokens: Arc::new(TokenManager {
+                client: client.clone(),
+                token_url: format!("{}/oauth/token", self.base_url),
+                token_data,
+                token: Mutex::new(None),
+                refreshing: AtomicBool::new(false),
+            }),
+            client,
+            album_url,
+            album: StdMutex::new(None),
+            requesting: Mutex::new(()),
         })
     }
 }
 
 pub struct Client {
-    token: Option<Token>,
-    token_data: TokenData,
+    tokens: Arc<TokenManager>,
     client: ReqwestClient,
-    album_id: String,
-    gfycats: Option<GfycatCollection>,
-    /// Time since last request
-    time_since_last_request: Option<Instant>,
+    /// The album endpoint the grant has access to
+    album_url: String,
+    /// Album contents, only locked to swap them so requests don't wait for each other
+    album: StdMutex<Option<Album>>,
+    /// Held while requesting the album, so it's requested once when it's needed by many at a time
+    requesting: Mutex<()>,
+}
+
+struct Album {
+    gfycats: Arc<GfycatCollection>,
+    requested_at: Instant,
 }
 
 impl Client {
-    pub async fn random_video(&mut self) -> Result<String, RequestError> {
-        // Cache is newer than last 24h
-        if self.gfycats.is_some()
-            && self.time_since_last_request.unwrap().elapsed().as_secs() < 60 * 60 * 24
-        {
-            let collection = self.gfycats.as_ref().unwrap();
-            let gfycat = collection.pick_random().unwrap();
-            return Ok(format!("https://gfycat.com/{}", gfycat.gfyId));
+    /// Random video with its media links, `None` if there are none that can be used
+    pub async fn random_clip(&self, nsfw: bool) -> Result<Option<Clip>, RequestError> {
+        let gfycats = self.collection().await?;
+        Ok(gfycats.pick_random(nsfw).map(Gfycat::clip))
+    }
+
+    /// Media links of the video `id`, `None` if it's not in the album
+    pub async fn clip(&self, id: &str) -> Result<Option<Clip>, RequestError> {
+        let gfycats = self.collection().await?;
+        Ok(gfycats
+            .0
+            .iter()
+            .find(|gfycat| gfycat.gfyId == id)
+            .map(Gfycat::clip))
+    }
+
+    /// Ids of every video in the album, the ones flagged as NSFW are left out unless `nsfw` is set
+    pub async fn video_ids(&self, nsfw: bool) -> Result<Vec<String>, RequestError> {
+        Ok(self
+            .collection()
+            .await?
+            .0
+            .iter()
+            .filter(|gfycat| nsfw || !gfycat.is_nsfw())
+            .map(|gfycat| gfycat.gfyId.clone())
+            .collect())
+    }
+
+    /// Album contents as JSON, `None` if it hasn't been requested yet
+    pub fn album_json(&self) -> Option<String> {
+        let gfycats = self.album.lock().unwrap().as_ref()?.gfycats.clone();
+        serde_json::to_string(&*gfycats).ok()
+    }
+
+    /// When the album contents were last requested
+    pub fn album_requested_at(&self) -> Option<Instant> {
+        self.album
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|album| album.requested_at)
+    }
+
+    /// Uses album contents from [`Client::album_json`] that were requested `age` ago,
+    /// so they don't have to be requested again after a restart
+    pub fn restore_album(&self, json: &str, age: Duration) -> serde_json::Result<()> {
+        *self.album.lock().unwrap() = Some(Album {
+            gfycats: Arc::new(serde_json::from_str(json)?),
+            requested_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        });
+        Ok(())
+    }
+
+    /// Album contents, requested again if the cached one is older than 24h
+    async fn collection(&self) -> Result<Arc<GfycatCollection>, RequestError> {
+        if let Some(gfycats) = self.cached_album() {
+            return Ok(gfycats);
         }
 
-        if self.token.is_none() {
-            self.token = Some(self.request_token().await?);
+        let _requesting = self.requesting.lock().await;
+        // Could have been requested while waiting for the lock
+        if let Some(gfycats) = self.cached_album() {
+            return Ok(gfycats);
         }
+        let gfycats = Arc::new(self.request_album().await?.publishedGfys);
+        *self.album.lock().unwrap() = Some(Album {
+            gfycats: gfycats.clone(),
+            requested_at: Instant::now(),
+        });
+
+        Ok(gfycats)
+    }
 
-        let token = self.token.as_ref().unwrap();
-        if !token.is_valid() {
-            self.token = Some(self.refresh_token().await?);
+    /// Album contents if they're newer than 24h
+    fn cached_album(&self) -> Option<Arc<GfycatCollection>> {
+        self.album
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|album| album.requested_at.elapsed().as_secs() < 60 * 60 * 24)
+            .map(|album| album.gfycats.clone())
+    }
+
+    /// Requests the album, with a new token once if the current one is rejected
+    async fn request_album(&self) -> Result<AlbumResponse, RequestError> {
+        let token = self.tokens.access_token().await?;
+        let mut response = self.get_album(&token).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            debug!("Gfycat token was rejected, retrying with a new one");
+            let token = self.tokens.replace(&token).await?;
+            response = self.get_album(&token).await?;
         }
 
-        let response = self.request_album().await?;
-        self.time_since_last_request = Some(Instant::now());
-        self.gfycats = Some(response.publishedGfys);
+        match response.status() {
+            status if status.is_success() => Ok(response.json::<AlbumResponse>().await?),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(RequestError::Auth(ApiError::read(response).await))
+            }
+            _ => Err(RequestError::Api(ApiError::read(response).await)),
+        }
+    }
 
-        let collection = self.gfycats.as_ref().unwrap();
-        let gfycat = collection.pick_random().unwrap();
+    async fn get_album(&self, access_token: &str) -> Result<Response, reqwest::Error> {
+        self.client
+            .get(&self.album_url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+    }
+}
+
+/// Keeps an access token around and refreshes it before it expires.
+///
+/// The first token is requested when it's needed, after that it's refreshed
+/// in the background until the manager is dropped.
+struct TokenManager {
+    client: ReqwestClient,
+    token_url: String,
+    token_data: TokenData,
+    token: Mutex<Option<Token>>,
+    /// Whether the background refresh has been started
+    refreshing: AtomicBool,
+}
+
+impl TokenManager {
+    /// Access token that's still valid, requested or refreshed first if needed
+    async fn access_token(self: &Arc<Self>) -> Result<String, RequestError> {
+        let mut token = self.token.lock().await;
+        match token.as_ref() {
+            Some(current) if current.is_valid() => (),
+            Some(_) => *token = Some(self.refresh(token.as_ref()).await?),
+            None => *token = Some(self.request().await?),
+        }
 
-        Ok(format!("https://gfycat.com/{}", gfycat.gfyId))
+        if !self.refreshing.swap(true, Ordering::SeqCst) {
+            spawn_refresh(Arc::downgrade(self));
+        }
+
+        Ok(token.as_ref().unwrap().access_token.clone())
+    }
+
+    /// New token in place of `rejected`, unless it has been replaced already
+    async fn replace(&self, rejected: &str) -> Result<String, RequestError> {
+        let mut token = self.token.lock().await;
+        if let Some(current) = token.as_ref() {
+            if current.access_token != rejected {
+                return Ok(current.access_token.clone());
+            }
+        }
+
+        let new = self.request().await?;
+        let access_token = new.access_token.clone();
+        *token = Some(new);
+        Ok(access_token)
     }
 
-    async fn request_token(&self) -> Result<Token, RequestError> {
+    /// Requests a new token with the configured grant
+    async fn request(&self) -> Result<Token, RequestError> {
         debug!("Requesting new gfycat token");
         let response = self
             .client
-            .post(TOKEN_URL)
+            .post(&self.token_url)
             .json(&self.token_data)
             .send()
             .await?;
 
-        if !response.status().is_success() {
-            return Err(RequestError::Gfycat(response.json::<GfycatError>().await?));
-        }
-        let mut token: Token = response.json().await?;
-        token.time_since_request = Some(Instant::now());
-
-        Ok(token)
+        Self::read_token(response).await
     }
 
-    async fn refresh_token(&self) -> Result<Token, RequestError> {
-        debug!("Refreshing gfycat token");
-        if self.token.is_none() {
-            return self.request_token().await;
-        }
-
-        if !self.token.as_ref().unwrap().is_refresh_valid() {
-            return self.request_token().await;
-        }
+    /// Refreshes `current`, or requests a new one if it can't be refreshed
+    async fn refresh(&self, current: Option<&Token>) -> Result<Token, RequestError> {
+        let refresh_token = match current {
+            Some(current) if current.is_refresh_valid() => current.refresh_token.as_deref(),
+            _ => None,
+        };
+        let refresh_token = match refresh_token {
+            Some(refresh_token) => refresh_token,
+            None => return self.request().await,
+        };
 
+        debug!("Refreshing gfycat token");
         let data = RefreshTokenData {
             client_id: &self.token_data.client_id,
-            client_secret: &self.token_data.client_secret,
-            refresh_token: &self.token.as_ref().unwrap().refresh_token,
+            client_secret: self.token_data.client_secret.expose(),
+            refresh_token,
             grant_type: GrantType::Refresh,
         };
+        let response = self.client.post(&self.token_url).json(&data).send().await?;
 
-        let response = self.client.post(TOKEN_URL).json(&data).send().await?;
+        match Self::read_token(response).await {
+            // The refresh token can be revoked before it expires
+            Err(RequestError::Auth(error)) => {
+                debug!("Refreshing gfycat token failed: {}", error);
+                self.request().await
+            }
+            result => result,
+        }
+    }
 
+    async fn read_token(response: Response) -> Result<Token, RequestError> {
         if !response.status().is_success() {
-            return Err(RequestError::Gfycat(response.json::<GfycatError>().await?));
+            let error = ApiError::read(response).await;
+            return Err(match error.is_auth() {
+                true => RequestError::Auth(error),
+                false => RequestError::Api(error),
+            });
         }
 
         let mut token: Token = response.json().await?;
@@ -288,22 +502,43 @@ impl Client {
 
         Ok(token)
     }
+}
 
-    async fn request_album(&self) -> Result<AlbumResponse, reqwest::Error> {
-        self.client
-            .get(&format!(
-                "https://api.gfycat.com/v1/me/albums/{}",
-                self.album_id
-            ))
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.token.as_ref().unwrap().access_token),
-            )
-            .send()
-            .await?
-            .json::<AlbumResponse>()
-            .await
-    }
+/// Refreshes the token ahead of its expiry until `tokens` is dropped
+fn spawn_refresh(tokens: Weak<TokenManager>) {
+    tokio::spawn(async move {
+        loop {
+            let wait = match tokens.upgrade() {
+                Some(tokens) => tokens
+                    .token
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map(Token::refresh_in)
+                    .unwrap_or_default(),
+                None => return,
+            };
+            time::sleep(wait.max(MIN_REFRESH_WAIT)).await;
+
+            let tokens = match tokens.upgrade() {
+                Some(tokens) => tokens,
+                None => return,
+            };
+            let mut token = tokens.token.lock().await;
+            if token.as_ref().map(Token::refresh_in) != Some(Duration::ZERO) {
+                continue;
+            }
+            match tokens.refresh(token.as_ref()).await {
+                Ok(new) => *token = Some(new),
+                Err(e) => warn!("Failed to refresh gfycat token: {}", e),
+            }
+        }
+    });
+}
+
+/// Link to the gfycat page of the video
+pub fn video_url(id: &str) -> String {
+    format!("https://gfycat.com/{}", id)
 }
 
 /// The actual token data returned by the API
@@ -311,9 +546,13 @@ impl Client {
 #[allow(dead_code)]
 struct Token {
     token_type: String,
+    /// Client credentials tokens can't be refreshed, so they don't have the refresh fields
+    #[serde(default)]
     refresh_token_expires_in: u32,
-    refresh_token: String,
+    refresh_token: Option<String>,
+    #[serde(default)]
     scope: String,
+    #[serde(default)]
     resource_owner: String,
     expires_in: u32,
     access_token: String,
@@ -324,6 +563,12 @@ struct Token {
 }
 
 impl Token {
+    /// How long until the token should be refreshed, zero if it's already due
+    fn refresh_in(&self) -> Duration {
+        let lifetime = Duration::from_secs(self.expires_in.into()).saturating_sub(REFRESH_AHEAD);
+        lifetime.saturating_sub(self.time_since_request.unwrap().elapsed())
+    }
+
     fn is_valid(&self) -> bool {
         // Add 5 secs since the request also has to take some time before it reaches the API
         self.time_since_request.unwrap().elapsed().as_secs() + 5 < self.expires_in as u64
@@ -341,7 +586,7 @@ struct AlbumResponse {
     publishedGfys: GfycatCollection,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, SerializeDerive)]
 #[allow(dead_code, non_snake_case)]
 struct Gfycat {
     gfyId: String,
@@ -399,29 +644,64 @@ struct Gfycat {
     iframeProfileImageVisible: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, SerializeDerive)]
 struct GfycatCollection(Vec<Gfycat>);
 
+impl Gfycat {
+    /// Gfycat marks NSFW videos with `1` or `3`, and safe ones with `0`
+    fn is_nsfw(&self) -> bool {
+        self.nsfw.as_deref().is_some_and(|nsfw| nsfw != "0")
+    }
+
+    /// The full size and mobile mp4s, the mobile one has no size listed
+    fn clip(&self) -> Clip {
+        let full = self.mp4Url.as_ref().map(|url| Rendition {
+            url: url.clone(),
+            size: self.mp4Size.as_deref().and_then(|size| size.parse().ok()),
+        });
+        let mobile = self.mobileUrl.as_ref().map(|url| Rendition {
+            url: url.clone(),
+            size: None,
+        });
+
+        Clip {
+            id: self.gfyId.clone(),
+            title: self.title.clone().filter(|title| !title.is_empty()),
+            page_url: video_url(&self.gfyId),
+            poster_url: self.posterUrl.clone(),
+            renditions: full.into_iter().chain(mobile).collect(),
+        }
+    }
+}
+
 impl GfycatCollection {
-    fn pick_random(&self) -> Option<&Gfycat> {
-        self.0.choose(&mut thread_rng())
+    fn pick_random(&self, nsfw: bool) -> Option<&Gfycat> {
+        let gfycats = self
+            .0
+            .iter()
+            .filter(|gfycat| nsfw || !gfycat.is_nsfw())
+            .collect::<Vec<_>>();
+        gfycats.choose(&mut thread_rng()).copied()
     }
 }
 
+#[cfg(test)]
+mod mock_server;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     mod token_data {
-        use super::{GrantType, TokenData};
+        use super::{GrantType, Secret, TokenData};
 
         #[test]
         fn serialize_password_grant() {
             let data = TokenData {
                 client_id: "foo".to_string(),
-                client_secret: "bar".to_string(),
+                client_secret: Secret::new("bar".to_string()),
                 username: Some("baz".to_string()),
-                password: Some("bas".to_string()),
+                password: Some(Secret::new("bas".to_string())),
                 grant_type: GrantType::Password,
             };
 
@@ -438,7 +718,7 @@ mod tests {
         fn serialize_client_credentials_grant() {
             let data = TokenData {
                 client_id: "foo".to_string(),
-                client_secret: "bar".to_string(),
+                client_secret: Secret::new("bar".to_string()),
                 username: None,
                 password: None,
                 grant_type: GrantType::ClientCredentials,
@@ -454,6 +734,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn refreshes_ahead_of_expiry() {
+        let token = |expires_in| Token {
+            token_type: String::new(),
+            refresh_token_expires_in: 7200,
+            refresh_token: None,
+            scope: String::new(),
+            resource_owner: String::new(),
+            expires_in,
+            access_token: String::new(),
+            time_since_request: Some(Instant::now()),
+        };
+
+        let wait = token(3600).refresh_in();
+        assert!(wait > Duration::from_secs(3530) && wait <= Duration::from_secs(3540));
+        assert_eq!(token(30).refresh_in(), Duration::ZERO);
+    }
+
     #[test]
     fn serialize_refresh_token_data() {
         let data = RefreshTokenData {
@@ -0,0 +1,418 @@
+//! Token, refresh and album requests against a local mock server
+
+use super::*;
+
+use mockito::{mock, server_url, Matcher, Mock};
+use serde_json::json;
+
+fn client() -> Client {
+    ClientBuilder::new(
+        "id".to_owned(),
+        Secret::new("secret".to_owned()),
+        "album".to_owned(),
+    )
+    .password_grant("user".to_owned(), Secret::new("pass".to_owned()))
+    .base_url(&server_url())
+    .build()
+    .unwrap()
+}
+
+fn token_json(access_token: &str, expires_in: u32, refresh_expires_in: u32) -> String {
+    json!({
+        "token_type": "bearer",
+        "refresh_token_expires_in": refresh_expires_in,
+        "refresh_token": format!("{}-refresh", access_token),
+        "scope": "",
+        "resource_owner": "user",
+        "expires_in": expires_in,
+        "access_token": access_token,
+    })
+    .to_string()
+}
+
+fn gfycat_json(id: &str, nsfw: &str) -> serde_json::Value {
+    json!({
+        "gfyId": id,
+        "gfyName": id,
+        "gfyNumber": "1",
+        "avgColor": "#000000",
+        "userName": "user",
+        "width": "640",
+        "height": "480",
+        "frameRate": "30",
+        "numFrames": "90",
+        "views": 1,
+        "nsfw": nsfw,
+        "fullDomainWhitelist": [],
+        "fullGeoWhitelist": [],
+        "iframeProfileImageVisible": false,
+    })
+}
+
+fn error_json(code: &str) -> String {
+    json!({
+        "errorMessage": {
+            "code": code,
+            "description": "Nope",
+        }
+    })
+    .to_string()
+}
+
+/// Password grant that answers with `token`
+fn password_mock(token: &str) -> Mock {
+    mock("POST", "/oauth/token")
+        .match_body(Matcher::PartialJson(json!({
+            "client_id": "id",
+            "client_secret": "secret",
+            "username": "user",
+            "password": "pass",
+            "grant_type": "password",
+        })))
+        .with_header("content-type", "application/json")
+        .with_body(token)
+}
+
+/// Album with a safe and an NSFW video, only answered for `access_token`
+fn album_mock(access_token: &str) -> Mock {
+    mock("GET", "/me/albums/album")
+        .match_header("authorization", format!("Bearer {}", access_token).as_str())
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "publishedGfys": [gfycat_json("SafeCat", "0"), gfycat_json("SpicyCat", "1")]
+            })
+            .to_string(),
+        )
+}
+
+/// Makes the next call request the album again
+fn expire_album(client: &Client) {
+    *client.album.lock().unwrap() = None;
+}
+
+#[tokio::test]
+async fn requests_token_and_album_once() {
+    let token = password_mock(&token_json("first", 3600, 7200))
+        .expect(1)
+        .create();
+    let album = album_mock("first").expect(1).create();
+
+    let client = client();
+    assert_eq!(client.video_ids(false).await.unwrap(), vec!["SafeCat"]);
+    assert_eq!(
+        client.video_ids(true).await.unwrap(),
+        vec!["SafeCat", "SpicyCat"]
+    );
+
+    token.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn refreshes_expired_token() {
+    let token = password_mock(&token_json("first", 0, 7200))
+        .expect(1)
+        .create();
+    let refresh = mock("POST", "/oauth/token")
+        .match_body(Matcher::PartialJson(json!({
+            "refresh_token": "first-refresh",
+            "grant_type": "refresh",
+        })))
+        .with_header("content-type", "application/json")
+        .with_body(token_json("second", 3600, 7200))
+        .expect(1)
+        .create();
+    let first_album = album_mock("first").expect(1).create();
+    let second_album = album_mock("second").expect(1).create();
+
+    let client = client();
+    client.video_ids(false).await.unwrap();
+    expire_album(&client);
+    client.video_ids(false).await.unwrap();
+
+    token.assert();
+    refresh.assert();
+    first_album.assert();
+    second_album.assert();
+}
+
+#[tokio::test]
+async fn requests_new_token_when_refresh_token_expired() {
+    let token = password_mock(&token_json("first", 0, 0)).expect(2).create();
+    let album = album_mock("first").expect(2).create();
+
+    let client = client();
+    client.video_ids(false).await.unwrap();
+    expire_album(&client);
+    client.video_ids(false).await.unwrap();
+
+    token.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn requests_new_token_when_refresh_is_rejected() {
+    let token = password_mock(&token_json("first", 0, 7200))
+        .expect(2)
+        .create();
+    let refresh = mock("POST", "/oauth/token")
+        .match_body(Matcher::PartialJson(json!({ "grant_type": "refresh" })))
+        .with_status(401)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("InvalidToken"))
+        .expect(1)
+        .create();
+    let album = album_mock("first").expect(2).create();
+
+    let client = client();
+    client.video_ids(false).await.unwrap();
+    expire_album(&client);
+    client.video_ids(false).await.unwrap();
+
+    token.assert();
+    refresh.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn keeps_rate_limited_refresh_from_logging_in() {
+    let token = password_mock(&token_json("first", 0, 7200))
+        .expect(1)
+        .create();
+    let refresh = mock("POST", "/oauth/token")
+        .match_body(Matcher::PartialJson(json!({ "grant_type": "refresh" })))
+        .with_status(429)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("TooManyRequests"))
+        .expect(1)
+        .create();
+    let album = album_mock("first").expect(1).create();
+
+    let client = client();
+    client.video_ids(false).await.unwrap();
+    expire_album(&client);
+    match client.video_ids(false).await {
+        Err(RequestError::Api(error)) => {
+            assert_eq!(error.status, StatusCode::TOO_MANY_REQUESTS)
+        }
+        other => panic!("Expected an API error, got {:?}", other.map(|_| ())),
+    }
+
+    token.assert();
+    refresh.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn requests_new_token_when_refresh_grant_is_invalid() {
+    let token = password_mock(&token_json("first", 0, 7200))
+        .expect(2)
+        .create();
+    let refresh = mock("POST", "/oauth/token")
+        .match_body(Matcher::PartialJson(json!({ "grant_type": "refresh" })))
+        .with_status(400)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("invalid_grant"))
+        .expect(1)
+        .create();
+    let album = album_mock("first").expect(2).create();
+
+    let client = client();
+    client.video_ids(false).await.unwrap();
+    expire_album(&client);
+    client.video_ids(false).await.unwrap();
+
+    token.assert();
+    refresh.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn retries_once_with_new_token() {
+    // Every token request gets a different token
+    let requested = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let token = mock("POST", "/oauth/token")
+        .with_header("content-type", "application/json")
+        .with_body_from_fn(move |body| {
+            let count = requested.fetch_add(1, Ordering::SeqCst) + 1;
+            body.write_all(token_json(&format!("token{}", count), 3600, 7200).as_bytes())
+        })
+        .expect(2)
+        .create();
+    let revoked = mock("GET", "/me/albums/album")
+        .match_header("authorization", "Bearer token1")
+        .with_status(401)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("Unauthorized"))
+        .expect(1)
+        .create();
+    let album = album_mock("token2").expect(1).create();
+
+    let client = client();
+    assert_eq!(client.video_ids(false).await.unwrap(), vec!["SafeCat"]);
+
+    token.assert();
+    revoked.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn reads_media_links() {
+    let _token = password_mock(&token_json("first", 3600, 7200)).create();
+    let mut gfycat = gfycat_json("SafeCat", "0");
+    gfycat["title"] = json!("Sleepy cat");
+    gfycat["mp4Url"] = json!("https://giant.gfycat.com/SafeCat.mp4");
+    gfycat["mp4Size"] = json!("1234");
+    gfycat["mobileUrl"] = json!("https://thumbs.gfycat.com/SafeCat-mobile.mp4");
+    gfycat["posterUrl"] = json!("https://thumbs.gfycat.com/SafeCat-poster.jpg");
+    let _album = mock("GET", "/me/albums/album")
+        .with_header("content-type", "application/json")
+        .with_body(json!({ "publishedGfys": [gfycat, gfycat_json("BareCat", "0")] }).to_string())
+        .create();
+
+    let client = client();
+    assert_eq!(
+        client.clip("SafeCat").await.unwrap(),
+        Some(Clip {
+            id: "SafeCat".to_owned(),
+            title: Some("Sleepy cat".to_owned()),
+            page_url: "https://gfycat.com/SafeCat".to_owned(),
+            poster_url: Some("https://thumbs.gfycat.com/SafeCat-poster.jpg".to_owned()),
+            renditions: vec![
+                Rendition {
+                    url: "https://giant.gfycat.com/SafeCat.mp4".to_owned(),
+                    size: Some(1234),
+                },
+                Rendition {
+                    url: "https://thumbs.gfycat.com/SafeCat-mobile.mp4".to_owned(),
+                    size: None,
+                },
+            ],
+        })
+    );
+    assert!(client
+        .clip("BareCat")
+        .await
+        .unwrap()
+        .is_some_and(|clip| clip.renditions.is_empty()));
+    assert_eq!(client.clip("GoneCat").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn reads_public_album_with_client_credentials() {
+    // Client credentials tokens have no refresh token, so a new one is requested each time
+    let token = mock("POST", "/oauth/token")
+        .match_body(Matcher::Json(json!({
+            "client_id": "id",
+            "client_secret": "secret",
+            "grant_type": "client_credentials",
+        })))
+        .with_header("content-type", "application/json")
+        .with_body(
+            json!({
+                "token_type": "bearer",
+                "scope": "",
+                "expires_in": 0,
+                "access_token": "app",
+            })
+            .to_string(),
+        )
+        .expect(2)
+        .create();
+    let album = mock("GET", "/users/owner/albums/album")
+        .match_header("authorization", "Bearer app")
+        .with_header("content-type", "application/json")
+        .with_body(json!({ "publishedGfys": [gfycat_json("SafeCat", "0")] }).to_string())
+        .expect(2)
+        .create();
+
+    let client = ClientBuilder::new(
+        "id".to_owned(),
+        Secret::new("secret".to_owned()),
+        "album".to_owned(),
+    )
+    .client_credentials_grant("owner".to_owned())
+    .base_url(&server_url())
+    .build()
+    .unwrap();
+    assert_eq!(client.video_ids(false).await.unwrap(), vec!["SafeCat"]);
+    expire_album(&client);
+    client.video_ids(false).await.unwrap();
+
+    token.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn reports_token_errors() {
+    let _token = mock("POST", "/oauth/token")
+        .with_status(401)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("InvalidGrant"))
+        .create();
+
+    match client().video_ids(false).await {
+        Err(RequestError::Auth(error)) => {
+            assert_eq!(error.status, StatusCode::UNAUTHORIZED);
+            assert_eq!(error.error.unwrap().errorMessage.code, "InvalidGrant");
+        }
+        other => panic!("Expected an auth error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn reports_rejected_album_requests() {
+    let token = password_mock(&token_json("first", 3600, 7200))
+        .expect(2)
+        .create();
+    let album = mock("GET", "/me/albums/album")
+        .with_status(401)
+        .with_header("content-type", "application/json")
+        .with_body(error_json("Unauthorized"))
+        .expect(2)
+        .create();
+
+    match client().video_ids(false).await {
+        Err(RequestError::Auth(error)) => {
+            assert_eq!(error.error.unwrap().errorMessage.code, "Unauthorized")
+        }
+        other => panic!("Expected an auth error, got {:?}", other.map(|_| ())),
+    }
+    // Retried only once
+    token.assert();
+    album.assert();
+}
+
+#[tokio::test]
+async fn reports_malformed_json() {
+    let _token = password_mock(&token_json("first", 3600, 7200)).create();
+    let _album = mock("GET", "/me/albums/album")
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"publishedGfys": [{"gfyId": "#)
+        .create();
+
+    let client = client();
+    match client.video_ids(false).await {
+        Err(RequestError::Transport(error)) => assert!(error.is_decode()),
+        other => panic!("Expected a decode error, got {:?}", other.map(|_| ())),
+    }
+    // Nothing is kept from the failed request
+    assert!(client.album_json().is_none());
+}
+
+#[tokio::test]
+async fn reports_server_errors() {
+    let _token = mock("POST", "/oauth/token")
+        .with_status(500)
+        .with_body("Internal Server Error")
+        .create();
+
+    match client().video_ids(false).await {
+        Err(RequestError::Api(error)) => {
+            assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(error.error.is_none());
+        }
+        other => panic!("Expected an API error, got {:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,64 @@
+use crate::config::BotConfig;
+
+use serenity::{
+    framework::standard::{macros::check, Reason},
+    model::prelude::*,
+    prelude::*,
+};
+
+/// Whether cat commands are allowed in the channel, the error is the message to show to the user
+pub async fn allowed(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> Result<(), String> {
+    let data = ctx.data.read().await;
+    match data.get::<BotConfig>() {
+        Some(config) => config.channel_allowed(guild_id, channel_id),
+        None => Ok(()),
+    }
+}
+
+/// Whether the channel is marked as NSFW, DMs never are.
+///
+/// The flag is read directly since `GuildChannel::is_nsfw` only counts text channels,
+/// and threads go by their parent channel.
+#[cfg(feature = "catvid")]
+pub async fn is_nsfw(ctx: &Context, channel_id: ChannelId) -> bool {
+    let channel = match guild_channel(ctx, channel_id).await {
+        Some(channel) => channel,
+        None => return false,
+    };
+
+    let thread = matches!(
+        channel.kind,
+        ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+    );
+    match (thread, channel.parent_id) {
+        (true, Some(parent_id)) => guild_channel(ctx, parent_id)
+            .await
+            .is_some_and(|parent| parent.nsfw),
+        (true, None) => false,
+        (false, _) => channel.nsfw,
+    }
+}
+
+#[cfg(feature = "catvid")]
+async fn guild_channel(ctx: &Context, channel_id: ChannelId) -> Option<GuildChannel> {
+    match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) => Some(channel),
+        Ok(_) => None,
+        Err(e) => {
+            tracing::warn!("Failed to get channel {}: {}", channel_id, e);
+            None
+        }
+    }
+}
+
+#[check]
+#[name = "Channel"]
+async fn channel_check(ctx: &Context, msg: &Message) -> Result<(), Reason> {
+    allowed(ctx, msg.guild_id, msg.channel_id)
+        .await
+        .map_err(Reason::User)
+}
@@ -1,48 +1,192 @@
-use rand::{seq::IteratorRandom, thread_rng};
+use crate::channels::{allowed, CHANNEL_CHECK};
+use crate::config::{CatSettings, GuildSettings};
+use crate::media::{
+    cache::{spawn_warm, ThumbnailCache},
+    encode::{encode_all, EncodeError, EncodedImage},
+    index::{spawn_rescan, MediaIndex},
+    pack::pack,
+    shuffle::Shuffler,
+};
+use crate::ratelimit::{limit, RATELIMIT_CHECK};
+use crate::storage::{record_served, Served};
+
 use serenity::{
+    builder::CreateApplicationCommand,
     framework::standard::{
         macros::{check, command},
         Args, CommandResult, Reason,
     },
-    model::prelude::*,
+    model::{
+        application::{
+            command::CommandOptionType,
+            interaction::{
+                application_command::{ApplicationCommandInteraction, CommandDataOptionValue},
+                InteractionResponseType,
+            },
+        },
+        prelude::*,
+    },
     prelude::*,
 };
-use std::{borrow::Cow, env, io::Cursor, path::PathBuf};
-use tracing::{debug, warn};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+/// How many times failed images are replaced with new picks before giving up
+const MAX_PICK_ROUNDS: usize = 3;
+
+#[derive(Debug)]
+pub enum CatError {
+    /// A single image couldn't be used, these are logged and replaced
+    Image {
+        path: PathBuf,
+        error: EncodeError,
+    },
+    /// None of the picked images could be used
+    NoUsableImages,
+    MissingConfig,
+}
+
+impl Error for CatError {}
+
+impl fmt::Display for CatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CatError::Image { path, error } => write!(f, "{}: {}", path.display(), error),
+            CatError::NoUsableImages => write!(f, "Couldn't find any usable cat images"),
+            CatError::MissingConfig => write!(f, "Failed to get Cat config"),
+        }
+    }
+}
 
 pub struct CatConfig {
+    library: CatLibrary,
+    /// Guilds that have their own settings
+    guilds: HashMap<GuildId, CatLibrary>,
+    /// Limits how many images are decoded and encoded at once across all commands
+    encoders: Arc<Semaphore>,
+    cache: Option<Arc<ThumbnailCache>>,
+}
+
+/// Images and limits of a single guild
+#[derive(Clone)]
+struct CatLibrary {
     max_images: u8,
-    image_path: PathBuf,
+    /// Max total size of the attachments in a single message, in bytes
+    upload_limit: usize,
+    images: Arc<MediaIndex>,
 }
 
 impl CatConfig {
-    pub fn new() -> Self {
-        let mut cat_count = 1;
-        match env::var("CAT_MAX_IMAGES") {
-            Ok(count) => {
-                cat_count = count.parse::<u8>().unwrap();
-            }
-            Err(_) => {
-                warn!("CAT_MAX_IMAGES env not found, defaulting to {}", cat_count);
+    pub fn new(
+        settings: &CatSettings,
+        guild_settings: &HashMap<GuildId, GuildSettings>,
+    ) -> Result<Self, String> {
+        // Guilds using the same folder share the index
+        let mut indexes = HashMap::new();
+        let mut library = |settings: &CatSettings| -> Result<CatLibrary, String> {
+            debug!("Cat image path set to: {}", settings.image_path.display());
+            let images = match indexes.get(&settings.image_path) {
+                Some(images) => Arc::clone(images),
+                None => {
+                    let images = Arc::new(
+                        MediaIndex::new(settings.image_path.clone(), IMAGE_EXTENSIONS).map_err(
+                            |e| {
+                                format!(
+                                    "Failed to read images from ({}): {}",
+                                    settings.image_path.display(),
+                                    e
+                                )
+                            },
+                        )?,
+                    );
+                    info!("Indexed {} cat images", images.len());
+                    spawn_rescan(
+                        images.clone(),
+                        Duration::from_secs(settings.rescan_interval),
+                    );
+                    indexes.insert(settings.image_path.clone(), images.clone());
+                    images
+                }
+            };
+
+            Ok(CatLibrary {
+                max_images: settings.max_images,
+                upload_limit: settings.upload_limit,
+                images,
+            })
+        };
+
+        let default = library(settings)?;
+        let mut guilds = HashMap::new();
+        for (guild_id, guild) in guild_settings {
+            if let Some(cat) = &guild.cat {
+                guilds.insert(*guild_id, library(cat)?);
             }
         }
-        debug!("Cat count set to: {}", cat_count);
 
-        let cat_path = env::var("CAT_IMAGE_PATH").expect("CAT_IMAGE_PATH has to be set in env");
-        debug!("Cat image path set to: {}", cat_path);
-        let path = PathBuf::from(&cat_path);
+        let encoders = Arc::new(Semaphore::new(settings.max_concurrent));
 
-        if !path.exists() {
-            panic!("Given path ({}) doesn't exist", cat_path);
-        }
-        if !path.is_dir() {
-            panic!("Given path ({}) is not directory", cat_path);
-        }
+        let cache = match &settings.cache_path {
+            Some(cache_path) => {
+                debug!("Cat cache path set to: {}", cache_path.display());
+                let cache = Arc::new(ThumbnailCache::new(cache_path.clone()).map_err(|e| {
+                    format!(
+                        "Failed to create cache dir ({}): {}",
+                        cache_path.display(),
+                        e
+                    )
+                })?);
 
-        CatConfig {
-            max_images: cat_count,
-            image_path: path,
-        }
+                let mut sources = HashSet::new();
+                for library in std::iter::once(&default).chain(guilds.values()) {
+                    for path in library.images.entries(None) {
+                        sources.insert((path, library.upload_limit));
+                    }
+                }
+                spawn_warm(
+                    cache.clone(),
+                    sources.into_iter().collect(),
+                    encoders.clone(),
+                );
+                Some(cache)
+            }
+            None => {
+                debug!("Cat cache path not set, images won't be cached");
+                None
+            }
+        };
+
+        Ok(CatConfig {
+            library: default,
+            guilds,
+            encoders,
+            cache,
+        })
+    }
+
+    /// Highest image count allowed in any guild
+    pub fn max_images(&self) -> u8 {
+        self.guilds
+            .values()
+            .map(|library| library.max_images)
+            .fold(self.library.max_images, u8::max)
+    }
+
+    /// Images and limits for `guild_id`
+    fn library(&self, guild_id: Option<GuildId>) -> &CatLibrary {
+        guild_id
+            .and_then(|guild_id| self.guilds.get(&guild_id))
+            .unwrap_or(&self.library)
     }
 }
 
@@ -51,99 +195,364 @@ impl TypeMapKey for CatConfig {
 }
 
 #[command]
-#[checks(CatCount)]
+#[checks(Channel, CatArgs, RateLimit)]
+#[usage("[album] [count]")]
+#[example("mittens 3")]
 #[min_args(0)]
-#[max_args(1)]
+#[max_args(2)]
 pub async fn cat(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let count = args.single::<u8>().unwrap_or(1);
-    debug!("Requested {} images", count);
+    let started = Instant::now();
+    let (album, count) = parse_args(&mut args)?;
+    let count = count.unwrap_or(1);
+    debug!("Requested {} images from album {:?}", count, album);
 
-    let data = ctx.data.read().await;
+    let messages = match cat_messages(
+        ctx,
+        msg.guild_id,
+        msg.channel_id,
+        album.as_deref(),
+        count as usize,
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => {
+            msg.channel_id.say(&ctx.http, e.to_string()).await?;
+            return Err(e.into());
+        }
+    };
+
+    for attachments in messages.messages {
+        msg.channel_id
+            .send_message(&ctx.http, |m| m.add_files(attachments))
+            .await?;
+    }
+
+    record_served(
+        ctx,
+        Served {
+            command: "cat".to_owned(),
+            guild_id: msg.guild_id,
+            channel_id: msg.channel_id,
+            user_id: msg.author.id,
+            served_at: SystemTime::now(),
+            latency: started.elapsed(),
+            bytes: messages.bytes,
+            items: messages.items,
+        },
+    )
+    .await;
+
+    Ok(())
+}
 
-    let config = data.get::<CatConfig>().ok_or("Failed to get Cat config")?;
-    let image_path = &config.image_path;
-
-    let images = image_path
-        .read_dir()?
-        .filter(|file| match file.as_ref().unwrap().path().extension() {
-            Some(ext) => matches!(
-                ext.to_str().unwrap().to_lowercase().as_str(),
-                "jpg" | "jpeg"
-            ),
-            None => false,
+/// Adds `/cat` to the application commands, `max_images` is the highest limit of all guilds
+pub fn register_cat(command: &mut CreateApplicationCommand, max_images: u8) {
+    command
+        .name("cat")
+        .description("Sends random cat images")
+        .create_option(|option| {
+            option
+                .name("count")
+                .description("How many images to send")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(max_images)
         })
-        .choose_multiple(&mut thread_rng(), count.into())
-        .iter()
-        .map(|file| file.as_ref().unwrap().path())
-        .collect::<Vec<std::path::PathBuf>>();
-
-    debug!("Sending files: {:?}", images);
-
-    let attachments = images
-        .iter()
-        .map(|image| {
-            let mut buffer = Cursor::new(Vec::new());
-            image::open(image)
-                .unwrap()
-                .thumbnail(1920, 1920)
-                .write_to(&mut buffer, image::ImageOutputFormat::Jpeg(100))
-                .unwrap();
-
-            AttachmentType::Bytes {
-                data: Cow::from(buffer.into_inner()),
-                filename: image.file_name().unwrap().to_str().unwrap().to_string(),
-            }
+        .create_option(|option| {
+            option
+                .name("album")
+                .description("Album to pick the images from")
+                .kind(CommandOptionType::String)
+        });
+}
+
+/// `/cat`, the application command version of [`cat`]
+pub async fn cat_slash(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let started = Instant::now();
+    let mut album = None;
+    let mut count = None;
+    for option in &command.data.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("album", Some(CommandDataOptionValue::String(value))) => album = Some(value.clone()),
+            ("count", Some(CommandDataOptionValue::Integer(value))) => count = Some(*value),
+            _ => (),
+        }
+    }
+
+    // The guild limits can be lower than the one Discord checks against
+    let invalid = match allowed(ctx, command.guild_id, command.channel_id).await {
+        Err(reason) => Some(reason),
+        Ok(()) => {
+            let data = ctx.data.read().await;
+            let config = data.get::<CatConfig>().ok_or(CatError::MissingConfig)?;
+            validate_args(config.library(command.guild_id), album.as_deref(), count).err()
+        }
+    };
+    let invalid = match invalid {
+        Some(reason) => Some(reason),
+        None => limit(
+            ctx,
+            "cat",
+            command.user.id,
+            command.channel_id,
+            command.guild_id,
+        )
+        .await
+        .err(),
+    };
+    if let Some(reason) = invalid {
+        command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|d| d.content(reason).ephemeral(true))
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let count = count.unwrap_or(1);
+    debug!("Requested {} images from album {:?}", count, album);
+
+    // Encoding can take longer than the 3 seconds Discord waits for a response
+    command
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
         })
-        .collect::<Vec<AttachmentType>>();
+        .await?;
 
-    //debug!("Sending file size: {:?}", attachment.iter().sum::<u16>());
+    let messages = match cat_messages(
+        ctx,
+        command.guild_id,
+        command.channel_id,
+        album.as_deref(),
+        count as usize,
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => {
+            command
+                .create_followup_message(&ctx.http, |m| m.content(e.to_string()))
+                .await?;
+            return Err(e.into());
+        }
+    };
+
+    for attachments in messages.messages {
+        command
+            .create_followup_message(&ctx.http, |m| m.add_files(attachments))
+            .await?;
+    }
+
+    record_served(
+        ctx,
+        Served {
+            command: "cat".to_owned(),
+            guild_id: command.guild_id,
+            channel_id: command.channel_id,
+            user_id: command.user.id,
+            served_at: SystemTime::now(),
+            latency: started.elapsed(),
+            bytes: messages.bytes,
+            items: messages.items,
+        },
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Images to send for a single use of the command
+struct CatMessages {
+    /// Attachments of each message
+    messages: Vec<Vec<AttachmentType<'static>>>,
+    /// Paths of the sent images
+    items: Vec<String>,
+    /// Total size of the attachments
+    bytes: u64,
+}
+
+/// Picks and encodes `count` images and splits them into messages that fit the upload limit
+async fn cat_messages(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    album: Option<&str>,
+    count: usize,
+) -> Result<CatMessages, CatError> {
+    let (paths, encoded): (Vec<_>, Vec<_>) = cat_images(ctx, guild_id, channel_id, album, count)
+        .await?
+        .into_iter()
+        .unzip();
+
+    let bytes = encoded.iter().map(|image| image.data.len()).sum::<usize>();
     debug!(
         "Attachment(s) size: {:.2?}MB",
-        attachments
-            .iter()
-            .map(|attachment| {
-                match attachment {
-                    AttachmentType::Bytes { data, filename: _ } => data.len(),
-                    _ => 0,
-                }
-            })
-            .sum::<usize>() as f64
-            / 1024.0
-            / 1024.0
+        bytes as f64 / 1024.0 / 1024.0
     );
 
-    msg.channel_id
-        .send_message(&ctx.http, |m| m.add_files(attachments))
-        .await?;
+    let upload_limit = {
+        let data = ctx.data.read().await;
+        let config = data.get::<CatConfig>().ok_or(CatError::MissingConfig)?;
+        config.library(guild_id).upload_limit
+    };
 
-    Ok(())
+    let messages = pack(encoded, upload_limit)
+        .into_iter()
+        .map(|message| {
+            message
+                .into_iter()
+                .map(|image| AttachmentType::Bytes {
+                    data: Cow::from(image.data),
+                    filename: image.filename,
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(CatMessages {
+        messages,
+        items: paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect(),
+        bytes: bytes as u64,
+    })
+}
+
+/// Picks `count` random images and encodes them for sending, along with their paths.
+///
+/// Images that fail to load are logged and replaced with new picks,
+/// so fewer images than requested are returned only if the album runs out.
+/// Picks come from the channel's shuffle bag when shuffling is enabled.
+async fn cat_images(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    album: Option<&str>,
+    count: usize,
+) -> Result<Vec<(PathBuf, EncodedImage)>, CatError> {
+    // Don't hold the lock while encoding
+    let (images, upload_limit, encoders, cache, shuffler) = {
+        let data = ctx.data.read().await;
+        let config = data.get::<CatConfig>().ok_or(CatError::MissingConfig)?;
+        let library = config.library(guild_id);
+
+        (
+            library.images.clone(),
+            library.upload_limit,
+            config.encoders.clone(),
+            config.cache.clone(),
+            data.get::<Shuffler>().cloned(),
+        )
+    };
+
+    let library = match album {
+        Some(album) => format!("cat:{}", album.to_lowercase()),
+        None => "cat".to_owned(),
+    };
+    let bag = shuffler.and_then(|shuffler| {
+        let key = shuffler.key(guild_id, channel_id, &library)?;
+        Some((shuffler, key))
+    });
+
+    let mut encoded = Vec::with_capacity(count);
+    let mut tried = HashSet::new();
+
+    for _ in 0..MAX_PICK_ROUNDS {
+        let missing = count - encoded.len();
+        let picks = match &bag {
+            Some((shuffler, key)) => {
+                let (images, album) = (images.clone(), album.map(str::to_owned));
+                let library = move || {
+                    images
+                        .entries(album.as_deref())
+                        .into_iter()
+                        .filter_map(|path| path.to_str().map(str::to_owned))
+                        .collect()
+                };
+                let drawn = shuffler
+                    .clone()
+                    .draw_blocking(key.clone(), missing, library, |path| {
+                        Path::new(path).is_file()
+                    })
+                    .await;
+
+                drawn
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .filter(|path| !tried.contains(path))
+                    .collect::<Vec<_>>()
+            }
+            // Pick extra so there are enough left after dropping the ones that were already tried
+            None => images
+                .choose(album, missing + tried.len())
+                .into_iter()
+                .filter(|path| !tried.contains(path))
+                .take(missing)
+                .collect::<Vec<_>>(),
+        };
+        if picks.is_empty() {
+            break;
+        }
+
+        debug!("Sending files: {:?}", picks);
+        tried.extend(picks.iter().cloned());
+
+        let results =
+            encode_all(picks.clone(), upload_limit, encoders.clone(), cache.clone()).await;
+        for (path, result) in picks.into_iter().zip(results) {
+            match result {
+                Ok(image) => encoded.push((path, image)),
+                Err(error) => warn!("Skipping image: {}", CatError::Image { path, error }),
+            }
+        }
+
+        if encoded.len() == count {
+            break;
+        }
+    }
+
+    if encoded.is_empty() {
+        return Err(CatError::NoUsableImages);
+    }
+
+    Ok(encoded)
+}
+
+/// Splits the arguments into an optional album name and an optional count.
+///
+/// A single numeric argument is treated as the count, otherwise the album comes first.
+fn parse_args(args: &mut Args) -> Result<(Option<String>, Option<i64>), &'static str> {
+    let mut album = None;
+    if args.remaining() > 1 || (!args.is_empty() && args.parse::<i64>().is_err()) {
+        album = Some(args.single::<String>().map_err(|_| "Invalid album name")?);
+    }
+
+    let mut count = None;
+    if !args.is_empty() {
+        count = Some(
+            args.single::<i64>()
+                .map_err(|_| "Count has to be positive integer")?,
+        );
+    }
+
+    Ok((album, count))
 }
 
 #[check]
-#[name = "CatCount"]
-async fn cat_count_check(ctx: &Context, _: &Message, args: &mut Args) -> Result<(), Reason> {
-    // Cat command defaults to 1 if no arg is given so we don't need to check anything
+#[name = "CatArgs"]
+async fn cat_args_check(ctx: &Context, msg: &Message, args: &mut Args) -> Result<(), Reason> {
+    // Cat command defaults to 1 image from all albums if no arg is given so we don't need to check anything
     if args.is_empty() {
         return Ok(());
     }
 
     let data = ctx.data.read().await;
 
-    match data.get::<CatConfig>() {
-        Some(config) => match args.single::<u8>() {
-            Ok(count) => {
-                if count < 1 {
-                    return Err(Reason::User("Count has to be at least 1".to_owned()));
-                }
-                if count > config.max_images {
-                    return Err(Reason::User(format!(
-                        "Count can be max {}",
-                        config.max_images
-                    )));
-                }
-            }
-            Err(_) => return Err(Reason::User("Count has to be positive integer".to_owned())),
-        },
+    let library = match data.get::<CatConfig>() {
+        Some(config) => config.library(msg.guild_id),
         None => {
             return Err(Reason::UserAndLog {
                 user: "Internal error".to_owned(),
@@ -152,9 +561,44 @@ async fn cat_count_check(ctx: &Context, _: &Message, args: &mut Args) -> Result<
         }
     };
 
+    let (album, count) = parse_args(args).map_err(|e| Reason::User(e.to_owned()))?;
+    validate_args(library, album.as_deref(), count).map_err(Reason::User)?;
+
     // Reset the args position so the command can get correct arguemnts
     // No need to reset it for failure states since it won't reach the command anyway
     args.restore();
 
     Ok(())
 }
+
+/// Checks that the album exists and the count is within the limits of `library`
+fn validate_args(
+    library: &CatLibrary,
+    album: Option<&str>,
+    count: Option<i64>,
+) -> Result<(), String> {
+    if let Some(album) = album {
+        if !library.images.has_album(album) {
+            let albums = library.images.albums();
+            if albums.is_empty() {
+                return Err("There are no albums".to_owned());
+            }
+            return Err(format!(
+                "Unknown album {}, available albums: {}",
+                album,
+                albums.join(", ")
+            ));
+        }
+    }
+
+    if let Some(count) = count {
+        if count < 1 {
+            return Err("Count has to be at least 1".to_owned());
+        }
+        if count > library.max_images.into() {
+            return Err(format!("Count can be max {}", library.max_images));
+        }
+    }
+
+    Ok(())
+}
@@ -1,54 +1,512 @@
-use crate::api::gfycat::{Client, ClientBuilder, RequestError};
+use crate::api::gfycat::ClientBuilder;
+use crate::channels::{allowed, is_nsfw, CHANNEL_CHECK};
+use crate::config::{CatvidSettings, GfycatLogin};
+use crate::media::{
+    index::{spawn_rescan, MediaIndex},
+    shuffle::Shuffler,
+};
+use crate::ratelimit::{limit, RATELIMIT_CHECK};
+use crate::storage::{record_served, Served, Storage};
+use crate::video::{
+    gfycat::GfycatSource,
+    local::{LocalSource, VIDEO_EXTENSIONS},
+    url_list::UrlListSource,
+    Clip, Video, VideoError, VideoSource,
+};
 use crate::CatvidConfigContainer;
 
 use serenity::{
+    builder::{CreateApplicationCommand, CreateEmbed},
     framework::standard::{macros::command, CommandResult},
-    model::prelude::*,
+    model::{
+        application::interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+        prelude::*,
+    },
     prelude::*,
 };
-use tracing::debug;
+use tracing::{debug, info, warn};
 
-use std::env;
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Clips are linked instead of uploaded above this when the source has no limit of its own
+const DEFAULT_UPLOAD_LIMIT: u64 = 8_000_000;
 
 pub struct CatvidConfig {
-    client: Client,
+    source: Box<dyn VideoSource>,
+    /// Largest clip that's downloaded and uploaded, in bytes
+    upload_limit: u64,
+    /// Downloads the clips that get uploaded
+    http: reqwest::Client,
 }
 
 impl CatvidConfig {
-    pub fn new() -> Self {
-        let client = ClientBuilder::new(
-            env::var("CATVID_CLIENT_ID").expect("CATVID_CLIENT_ID missing"),
-            env::var("CATVID_CLIENT_SECRET").expect("CATVID_CLIENT_SECRET missing"),
-            env::var("CATVID_ALBUM_ID").expect("CATVID_ALBUM_ID missing"),
-        )
-        .password_grant(
-            env::var("CATVID_USERNAME").expect("CATVID_USERNAME missing"),
-            env::var("CATVID_PASSWORD").expect("CATVID_PASSWORD missing"),
-        )
-        .build()
-        .unwrap();
+    /// Sets up the configured source, `storage` is used to keep remote album contents around
+    pub fn new(settings: &CatvidSettings, storage: &Arc<Storage>) -> Result<Self, String> {
+        let source: Box<dyn VideoSource> = match settings {
+            CatvidSettings::Gfycat {
+                client_id,
+                client_secret,
+                album_id,
+                username,
+                login,
+                api_url,
+                upload_limit: _,
+            } => {
+                debug!("Catvid source set to gfycat album {}", album_id);
+                let builder =
+                    ClientBuilder::new(client_id.clone(), client_secret.clone(), album_id.clone());
+                let builder = match api_url {
+                    Some(api_url) => builder.base_url(api_url),
+                    None => builder,
+                };
+                let builder = match login {
+                    GfycatLogin::Password(password) => {
+                        builder.password_grant(username.clone(), password.clone())
+                    }
+                    GfycatLogin::ClientCredentials => {
+                        builder.client_credentials_grant(username.clone())
+                    }
+                };
+                Box::new(GfycatSource::new(
+                    builder.build().map_err(|e| e.to_string())?,
+                    album_id,
+                    storage.clone(),
+                ))
+            }
+            CatvidSettings::Local {
+                path,
+                upload_limit,
+                rescan_interval,
+            } => {
+                debug!("Catvid source set to local folder {}", path.display());
+                let videos = Arc::new(
+                    MediaIndex::with_max_size(path.clone(), VIDEO_EXTENSIONS, *upload_limit)
+                        .map_err(|e| {
+                            format!("Failed to read videos from ({}): {}", path.display(), e)
+                        })?,
+                );
+                info!("Indexed {} cat videos", videos.len());
+                spawn_rescan(videos.clone(), Duration::from_secs(*rescan_interval));
+                Box::new(LocalSource::new(videos))
+            }
+            CatvidSettings::Urls { file } => {
+                debug!("Catvid source set to url list {}", file.display());
+                Box::new(UrlListSource::new(file.clone()))
+            }
+        };
+
+        let upload_limit = match settings {
+            CatvidSettings::Gfycat { upload_limit, .. }
+            | CatvidSettings::Local { upload_limit, .. } => *upload_limit,
+            CatvidSettings::Urls { .. } => DEFAULT_UPLOAD_LIMIT,
+        };
 
-        CatvidConfig { client }
+        Ok(CatvidConfig {
+            source,
+            upload_limit,
+            http: reqwest::Client::new(),
+        })
     }
 
-    async fn random_video(&mut self) -> Result<String, RequestError> {
-        self.client.random_video().await
+    /// Picks a random video, from the shuffle bag `key` if it's given.
+    /// Videos flagged as NSFW are only picked if `nsfw` is set.
+    async fn pick(
+        &self,
+        shuffler: Option<(Arc<Shuffler>, String)>,
+        nsfw: bool,
+    ) -> Result<Video, VideoError> {
+        let (shuffler, key) = match shuffler {
+            Some(bag) => bag,
+            None => return self.source.random(nsfw).await,
+        };
+
+        let ids = self.source.videos(nsfw).await?;
+        let id = shuffler
+            .draw_blocking(key, 1, move || ids, |_| true)
+            .await
+            .pop()
+            .ok_or(VideoError::Empty)?;
+
+        self.source.resolve(&id).await
     }
 }
 
 #[command]
+#[checks(Channel, RateLimit)]
 pub async fn catvid(ctx: &Context, msg: &Message) -> CommandResult {
-    let data = ctx.data.read().await;
+    let started = Instant::now();
+    let post = match pick_video(ctx, msg.guild_id, msg.channel_id).await {
+        Ok(post) => post,
+        Err(e) => {
+            let reply = failure_message(&*e);
+            msg.channel_id.say(&ctx.http, reply).await?;
+            return Err(e);
+        }
+    };
+    debug!("Sending {}", post.item());
+
+    match &post {
+        Post::Link(url) => {
+            msg.channel_id.say(&ctx.http, url).await?;
+        }
+        Post::File(path) => {
+            msg.channel_id
+                .send_files(&ctx.http, vec![path], |m| m)
+                .await?;
+        }
+        Post::Upload { clip, data } => {
+            msg.channel_id
+                .send_message(&ctx.http, |m| {
+                    m.add_file(attachment(clip, data))
+                        .embed(|e| clip_embed(e, clip, true))
+                })
+                .await?;
+        }
+        Post::Embed(clip) => {
+            msg.channel_id
+                .send_message(&ctx.http, |m| m.embed(|e| clip_embed(e, clip, false)))
+                .await?;
+        }
+    }
+
+    record_served(
+        ctx,
+        served(&post, msg.guild_id, msg.channel_id, msg.author.id, started).await,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Adds `/catvid` to the application commands
+pub fn register_catvid(command: &mut CreateApplicationCommand) {
+    command
+        .name("catvid")
+        .description("Sends a random cat video");
+}
 
-    let container = data
-        .get::<CatvidConfigContainer>()
-        .ok_or_else(|| "Failed to get CatvidConfig".to_string())?;
-    let mut config = container.lock().await;
+/// `/catvid`, the application command version of [`catvid`]
+pub async fn catvid_slash(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let started = Instant::now();
+    let allowed = match allowed(ctx, command.guild_id, command.channel_id).await {
+        Ok(()) => {
+            limit(
+                ctx,
+                "catvid",
+                command.user.id,
+                command.channel_id,
+                command.guild_id,
+            )
+            .await
+        }
+        Err(reason) => Err(reason),
+    };
+    if let Err(reason) = allowed {
+        command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|d| d.content(reason).ephemeral(true))
+            })
+            .await?;
+        return Ok(());
+    }
+
+    // Picking can take longer than the 3 seconds Discord waits for a response
+    command
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        })
+        .await?;
 
-    let video = config.random_video().await?;
-    debug!("Sending {}", video);
+    let post = match pick_video(ctx, command.guild_id, command.channel_id).await {
+        Ok(post) => post,
+        Err(e) => {
+            command
+                .create_followup_message(&ctx.http, |m| m.content(failure_message(&*e)))
+                .await?;
+            return Err(e);
+        }
+    };
+    debug!("Sending {}", post.item());
 
-    msg.channel_id.say(&ctx.http, video).await?;
+    match &post {
+        Post::Link(url) => {
+            command
+                .create_followup_message(&ctx.http, |m| m.content(url))
+                .await?;
+        }
+        Post::File(path) => {
+            command
+                .create_followup_message(&ctx.http, |m| m.add_file(path))
+                .await?;
+        }
+        Post::Upload { clip, data } => {
+            command
+                .create_followup_message(&ctx.http, |m| {
+                    m.add_file(attachment(clip, data))
+                        .embed(|e| clip_embed(e, clip, true))
+                })
+                .await?;
+        }
+        Post::Embed(clip) => {
+            command
+                .create_followup_message(&ctx.http, |m| m.embed(|e| clip_embed(e, clip, false)))
+                .await?;
+        }
+    }
+
+    record_served(
+        ctx,
+        served(
+            &post,
+            command.guild_id,
+            command.channel_id,
+            command.user.id,
+            started,
+        )
+        .await,
+    )
+    .await;
 
     Ok(())
 }
+
+/// What to tell the user when [`pick_video`] fails
+fn failure_message(error: &(dyn std::error::Error + 'static)) -> &'static str {
+    match error.downcast_ref::<VideoError>() {
+        Some(VideoError::Empty) => "There are no cat videos that can be sent here",
+        _ => "Failed to get a cat video",
+    }
+}
+
+/// How a picked video is sent
+enum Post {
+    /// Link that Discord embeds by itself
+    Link(String),
+    /// Local file that's uploaded
+    File(PathBuf),
+    /// Clip that fits the upload limit, uploaded with an embed linking to its page
+    Upload { clip: Clip, data: Vec<u8> },
+    /// Clip that's too large to upload, sent as an embed with its poster and links
+    Embed(Clip),
+}
+
+impl Post {
+    /// What the stats record as sent
+    fn item(&self) -> String {
+        match self {
+            Post::Link(url) => url.clone(),
+            Post::File(path) => path.to_string_lossy().into_owned(),
+            Post::Upload { clip, .. } | Post::Embed(clip) => clip.page_url.clone(),
+        }
+    }
+}
+
+/// Picks a video for the channel, from its shuffle bag when shuffling is enabled,
+/// and downloads it if it's a clip that can be uploaded
+async fn pick_video(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> Result<Post, Box<dyn std::error::Error + Send + Sync>> {
+    let nsfw = is_nsfw(ctx, channel_id).await;
+    // NSFW channels get their own bags so safe channels don't use up their videos
+    let library = match nsfw {
+        true => "catvid:nsfw",
+        false => "catvid",
+    };
+
+    // Not kept locked while the source makes its requests, so a reload doesn't wait for them
+    let (config, shuffler) = {
+        let data = ctx.data.read().await;
+        let config = data
+            .get::<CatvidConfigContainer>()
+            .cloned()
+            .ok_or_else(|| "Failed to get CatvidConfig".to_string())?;
+        (config, data.get::<Shuffler>().cloned())
+    };
+
+    let bag = shuffler.and_then(|shuffler| {
+        let key = shuffler.key(guild_id, channel_id, library)?;
+        Some((shuffler, key))
+    });
+    let video = config.pick(bag, nsfw).await?;
+
+    Ok(match video {
+        Video::Url(url) => Post::Link(url),
+        Video::File(path) => Post::File(path),
+        Video::Clip(clip) => prepare_clip(&config.http, clip, config.upload_limit).await,
+    })
+}
+
+/// Downloads the best rendition of `clip` that fits in `limit`, or falls back to an embed.
+/// Only a clip without any media is sent as a link to its page.
+async fn prepare_clip(http: &reqwest::Client, clip: Clip, limit: u64) -> Post {
+    if clip.renditions.is_empty() {
+        return Post::Link(clip.page_url);
+    }
+
+    let mut upload = None;
+    for rendition in clip.upload_candidates(limit) {
+        match download(http, &rendition.url, limit).await {
+            Ok(Some(data)) => {
+                upload = Some(data);
+                break;
+            }
+            Ok(None) => debug!("{} is larger than the upload limit", rendition.url),
+            Err(e) => warn!("Failed to download {}: {}", rendition.url, e),
+        }
+    }
+
+    match upload {
+        Some(data) => Post::Upload { clip, data },
+        None => Post::Embed(clip),
+    }
+}
+
+/// Downloads `url`, `None` if it turns out to be larger than `limit` bytes
+async fn download(
+    http: &reqwest::Client,
+    url: &str,
+    limit: u64,
+) -> Result<Option<Vec<u8>>, reqwest::Error> {
+    let mut response = http.get(url).send().await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Ok(None);
+    }
+
+    // The length isn't always given, so stop once there's too much
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        data.extend_from_slice(&chunk);
+        if data.len() as u64 > limit {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(data))
+}
+
+/// Downloaded `data` of `clip` as an mp4 attachment
+fn attachment<'a>(clip: &Clip, data: &'a [u8]) -> AttachmentType<'a> {
+    AttachmentType::Bytes {
+        data: Cow::Borrowed(data),
+        filename: format!("{}.mp4", clip.id),
+    }
+}
+
+/// Embed with the title of `clip` linking to its page.
+/// When the video isn't `attached` the poster and a direct link to the video are added.
+fn clip_embed<'a>(embed: &'a mut CreateEmbed, clip: &Clip, attached: bool) -> &'a mut CreateEmbed {
+    embed
+        .title(clip.title.as_deref().unwrap_or(&clip.id))
+        .url(&clip.page_url);
+    if !attached {
+        if let Some(poster_url) = &clip.poster_url {
+            embed.image(poster_url);
+        }
+        if let Some(url) = clip.best_url() {
+            embed.description(format!("[Watch the video]({})", url));
+        }
+    }
+    embed
+}
+
+/// Stats entry for sending `post`, uploads count towards the size but links and embeds don't
+async fn served(
+    post: &Post,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    user_id: UserId,
+    started: Instant,
+) -> Served {
+    let bytes = match post {
+        Post::Link(_) | Post::Embed(_) => 0,
+        Post::File(path) => tokio::fs::metadata(path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0),
+        Post::Upload { data, .. } => data.len() as u64,
+    };
+
+    Served {
+        command: "catvid".to_owned(),
+        guild_id,
+        channel_id,
+        user_id,
+        served_at: SystemTime::now(),
+        latency: started.elapsed(),
+        bytes,
+        items: vec![post.item()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::Rendition;
+
+    use mockito::{mock, server_url};
+
+    fn clip_with(renditions: Vec<Rendition>) -> Clip {
+        Clip {
+            id: "Cat".to_owned(),
+            title: None,
+            page_url: "https://example.com/Cat".to_owned(),
+            poster_url: None,
+            renditions,
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_first_rendition_that_fits() {
+        // Listed as fitting but turns out to be larger
+        let _full = mock("GET", "/full.mp4").with_body([0; 200]).create();
+        let _mobile = mock("GET", "/mobile.mp4").with_body([0; 50]).create();
+        let clip = clip_with(vec![
+            Rendition {
+                url: format!("{}/full.mp4", server_url()),
+                size: Some(100),
+            },
+            Rendition {
+                url: format!("{}/mobile.mp4", server_url()),
+                size: None,
+            },
+        ]);
+        let http = reqwest::Client::new();
+
+        match prepare_clip(&http, clip.clone(), 150).await {
+            Post::Upload { data, .. } => assert_eq!(data.len(), 50),
+            other => panic!("Expected an upload, got {}", other.item()),
+        }
+        assert!(matches!(
+            prepare_clip(&http, clip, 10).await,
+            Post::Embed(_)
+        ));
+        assert!(matches!(
+            prepare_clip(&http, clip_with(Vec::new()), 150).await,
+            Post::Link(url) if url == "https://example.com/Cat"
+        ));
+    }
+
+    #[test]
+    fn explains_missing_videos() {
+        let empty: Box<dyn std::error::Error + Send + Sync> = Box::new(VideoError::Empty);
+        assert_eq!(
+            failure_message(&*empty),
+            "There are no cat videos that can be sent here"
+        );
+        let other: Box<dyn std::error::Error + Send + Sync> = "Failed to get CatvidConfig".into();
+        assert_eq!(failure_message(&*other), "Failed to get a cat video");
+    }
+}
@@ -1,2 +1,8 @@
+#[cfg(feature = "cat")]
 pub mod cat;
+#[cfg(feature = "catvid")]
 pub mod catvid;
+pub mod prefix;
+pub mod reload;
+pub mod slash;
+pub mod stats;
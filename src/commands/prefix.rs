@@ -0,0 +1,199 @@
+use crate::config::BotConfig;
+use crate::storage::{Storage, StorageError};
+
+use serenity::{
+    framework::standard::{
+        macros::{command, group},
+        Args, CommandResult,
+    },
+    model::prelude::*,
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio::{sync::Mutex, task};
+use tracing::info;
+
+const MAX_PREFIX_LENGTH: usize = 16;
+
+/// Guild setting the prefix is stored under
+const PREFIX_SETTING: &str = "prefix";
+
+/// Prefixes guild admins have set with `prefix set`, these take precedence over the config.
+///
+/// Kept in memory as well since the prefix is needed for every message.
+pub struct PrefixStore {
+    storage: Arc<Storage>,
+    prefixes: RwLock<HashMap<GuildId, String>>,
+    /// Held while saving so concurrent changes can't end up in a different order
+    saving: Mutex<()>,
+}
+
+impl PrefixStore {
+    /// Creates the store and loads the saved prefixes from `storage`
+    pub fn new(storage: Arc<Storage>) -> Result<Self, StorageError> {
+        let prefixes = storage
+            .guild_settings(PREFIX_SETTING)?
+            .into_iter()
+            .collect();
+
+        Ok(PrefixStore {
+            storage,
+            prefixes: RwLock::new(prefixes),
+            saving: Mutex::new(()),
+        })
+    }
+
+    pub fn get(&self, guild_id: GuildId) -> Option<String> {
+        self.prefixes.read().unwrap().get(&guild_id).cloned()
+    }
+
+    /// Sets the prefix of `guild_id` and saves it, `None` goes back to the configured one.
+    ///
+    /// The prefixes are only locked once it's saved, so reading them never waits for the database.
+    pub async fn set(&self, guild_id: GuildId, prefix: Option<String>) -> CommandResult {
+        let _saving = self.saving.lock().await;
+        let storage = self.storage.clone();
+        let value = prefix.clone();
+        task::spawn_blocking(move || {
+            storage.set_guild_setting(guild_id, PREFIX_SETTING, value.as_deref())
+        })
+        .await??;
+
+        let mut prefixes = self.prefixes.write().unwrap();
+        match prefix {
+            Some(prefix) => prefixes.insert(guild_id, prefix),
+            None => prefixes.remove(&guild_id),
+        };
+
+        Ok(())
+    }
+}
+
+impl TypeMapKey for PrefixStore {
+    type Value = Arc<PrefixStore>;
+}
+
+/// Prefix to use for `guild_id`, the stored one or the one from the config
+pub fn prefix(data: &TypeMap, guild_id: Option<GuildId>) -> Option<String> {
+    let stored = guild_id.and_then(|guild_id| data.get::<PrefixStore>()?.get(guild_id));
+    match stored {
+        Some(prefix) => Some(prefix),
+        None => Some(data.get::<BotConfig>()?.prefix(guild_id).to_owned()),
+    }
+}
+
+fn validate_prefix(prefix: &str) -> Result<(), String> {
+    if prefix.chars().count() > MAX_PREFIX_LENGTH {
+        return Err(format!(
+            "Prefix can be at most {} characters long",
+            MAX_PREFIX_LENGTH
+        ));
+    }
+    if prefix.chars().any(char::is_whitespace) {
+        return Err("Prefix can't contain spaces".to_string());
+    }
+    Ok(())
+}
+
+#[group]
+#[prefixes("prefix")]
+#[only_in(guilds)]
+#[default_command(show)]
+#[commands(set, reset)]
+struct Prefix;
+
+/// Shows the prefix used in this server
+#[command]
+#[max_args(0)]
+async fn show(ctx: &Context, msg: &Message) -> CommandResult {
+    let prefix = prefix(&*ctx.data.read().await, msg.guild_id).unwrap_or_default();
+    msg.channel_id
+        .say(&ctx.http, format!("Prefix in this server is `{}`", prefix))
+        .await?;
+
+    Ok(())
+}
+
+/// Changes the prefix used in this server
+#[command]
+#[required_permissions(ADMINISTRATOR)]
+#[usage("<prefix>")]
+#[example("!")]
+#[num_args(1)]
+async fn set(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let prefix = args.single::<String>()?;
+    if let Err(e) = validate_prefix(&prefix) {
+        msg.channel_id.say(&ctx.http, e).await?;
+        return Ok(());
+    }
+
+    update(ctx, msg, Some(prefix.clone())).await?;
+    msg.channel_id
+        .say(&ctx.http, format!("Prefix changed to `{}`", prefix))
+        .await?;
+
+    Ok(())
+}
+
+/// Goes back to the prefix from the config
+#[command]
+#[required_permissions(ADMINISTRATOR)]
+#[max_args(0)]
+async fn reset(ctx: &Context, msg: &Message) -> CommandResult {
+    update(ctx, msg, None).await?;
+    let prefix = prefix(&*ctx.data.read().await, msg.guild_id).unwrap_or_default();
+    msg.channel_id
+        .say(&ctx.http, format!("Prefix changed back to `{}`", prefix))
+        .await?;
+
+    Ok(())
+}
+
+async fn update(ctx: &Context, msg: &Message, prefix: Option<String>) -> CommandResult {
+    let guild_id = msg
+        .guild_id
+        .ok_or("Prefix can only be changed in servers")?;
+    let store = ctx
+        .data
+        .read()
+        .await
+        .get::<PrefixStore>()
+        .cloned()
+        .ok_or("Failed to get PrefixStore")?;
+
+    info!("Setting prefix of guild {} to {:?}", guild_id, prefix);
+    store.set(guild_id, prefix).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_prefix() {
+        assert!(validate_prefix("!").is_ok());
+        assert!(validate_prefix("taribot,").is_ok());
+        assert!(validate_prefix("a b").is_err());
+        assert!(validate_prefix(&"!".repeat(MAX_PREFIX_LENGTH + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_prefixes() {
+        let storage = Arc::new(Storage::in_memory().unwrap());
+
+        let store = PrefixStore::new(storage.clone()).unwrap();
+        store.set(GuildId(1), Some("!".to_string())).await.unwrap();
+        store.set(GuildId(2), Some("?".to_string())).await.unwrap();
+        store.set(GuildId(2), None).await.unwrap();
+        assert_eq!(store.get(GuildId(1)).as_deref(), Some("!"));
+
+        let restored = PrefixStore::new(storage).unwrap();
+        assert_eq!(restored.get(GuildId(1)).as_deref(), Some("!"));
+        assert_eq!(restored.get(GuildId(2)), None);
+    }
+}
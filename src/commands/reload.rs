@@ -0,0 +1,25 @@
+use crate::reload;
+
+use serenity::{
+    framework::standard::{macros::command, CommandResult},
+    model::prelude::*,
+    prelude::*,
+};
+
+/// Reloads the config without restarting the bot
+#[command("reload")]
+#[owners_only]
+#[max_args(0)]
+pub async fn reload_config(ctx: &Context, msg: &Message) -> CommandResult {
+    let reply = match reload::reload(&ctx.data, &ctx.http).await {
+        Ok(restart_needed) if restart_needed.is_empty() => "Config reloaded".to_string(),
+        Ok(restart_needed) => format!(
+            "Config reloaded, changes to {} need a restart",
+            restart_needed.join(", ")
+        ),
+        Err(e) => format!("Keeping the old config:\n```\n{}\n```", e),
+    };
+    msg.channel_id.say(&ctx.http, reply).await?;
+
+    Ok(())
+}
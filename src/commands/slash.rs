@@ -0,0 +1,120 @@
+//! Application command versions of the prefix commands, so they work without the message content intent
+
+#[cfg(feature = "cat")]
+use super::cat::{cat_slash, register_cat, CatConfig};
+#[cfg(feature = "catvid")]
+use super::catvid::{catvid_slash, register_catvid};
+use crate::shutdown::InFlight;
+use crate::storage::{record_use, CommandUse};
+#[cfg(feature = "catvid")]
+use crate::CatvidConfigContainer;
+
+use serenity::{
+    framework::standard::CommandResult,
+    http::Http,
+    model::application::{
+        command::Command,
+        interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+    },
+    prelude::*,
+};
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+
+/// Registers the slash versions of the enabled commands, replacing the ones registered before
+#[cfg_attr(
+    not(any(feature = "cat", feature = "catvid")),
+    allow(unused_mut, unused_variables)
+)]
+pub async fn register(data: &RwLock<TypeMap>, http: &Http) -> serenity::Result<()> {
+    let mut commands = Vec::new();
+    {
+        let data = data.read().await;
+        #[cfg(feature = "cat")]
+        if let Some(config) = data.get::<CatConfig>() {
+            let mut command = Default::default();
+            register_cat(&mut command, config.max_images());
+            commands.push(command);
+        }
+        #[cfg(feature = "catvid")]
+        if data.contains_key::<CatvidConfigContainer>() {
+            let mut command = Default::default();
+            register_catvid(&mut command);
+            commands.push(command);
+        }
+    }
+
+    let registered =
+        Command::set_global_application_commands(http, |c| c.set_application_commands(commands))
+            .await?;
+    info!("Registered {} slash commands", registered.len());
+
+    Ok(())
+}
+
+/// Runs the command `command` was sent for
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(unused_variables))]
+pub async fn dispatch(ctx: &Context, command: &ApplicationCommandInteraction) {
+    let in_flight = ctx.data.read().await.get::<InFlight>().cloned();
+    if in_flight
+        .as_ref()
+        .is_some_and(|in_flight| !in_flight.begin())
+    {
+        info!("Shutting down, ignoring /{}", command.data.name);
+        if let Err(e) = reply_ephemeral(ctx, command, "Restarting, try again in a bit").await {
+            error!("{}", e);
+        }
+        return;
+    }
+
+    let result: CommandResult = match command.data.name.as_str() {
+        #[cfg(feature = "cat")]
+        "cat" => cat_slash(ctx, command).await,
+        #[cfg(feature = "catvid")]
+        "catvid" => catvid_slash(ctx, command).await,
+        // Registered before the command was disabled or by a build without it
+        _ => {
+            warn!("Unknown slash command {}", command.data.name);
+            reply_ephemeral(ctx, command, "This command isn't available")
+                .await
+                .map_err(Into::into)
+        }
+    };
+
+    if let Err(e) = &result {
+        error!("Slash command {} failed: {}", command.data.name, e);
+    }
+
+    record_use(
+        ctx,
+        CommandUse {
+            command: command.data.name.clone(),
+            guild_id: command.guild_id,
+            channel_id: command.channel_id,
+            user_id: command.user.id,
+            used_at: SystemTime::now(),
+            success: result.is_ok(),
+        },
+    )
+    .await;
+
+    if let Some(in_flight) = in_flight {
+        in_flight.end();
+    }
+}
+
+/// Answers `command` with a message only its user sees
+async fn reply_ephemeral(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    content: &str,
+) -> serenity::Result<()> {
+    command
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(content).ephemeral(true))
+        })
+        .await
+}
@@ -0,0 +1,164 @@
+use crate::storage::{Storage, UsageStats};
+
+use serenity::{
+    framework::standard::{
+        macros::{command, group},
+        CommandResult,
+    },
+    model::prelude::*,
+    prelude::*,
+};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::task;
+
+/// How far back `stats` looks
+const STATS_DAYS: u64 = 14;
+/// Length of the top lists
+const TOP_COUNT: usize = 5;
+/// Longer item names are cut short, file names can be up to 255 characters
+const MAX_NAME_LENGTH: usize = 64;
+/// Most characters Discord allows in an embed field
+const MAX_FIELD_LENGTH: usize = 1024;
+
+#[group]
+#[prefixes("stats")]
+#[default_command(show_stats)]
+#[commands(export_stats)]
+struct Stats;
+
+/// Shows the most sent cats, who asks for them the most and how many are asked for each day
+#[command("show")]
+#[only_in(guilds)]
+#[max_args(0)]
+async fn show_stats(ctx: &Context, msg: &Message) -> CommandResult {
+    let storage = storage(ctx).await?;
+    let guild_id = msg.guild_id;
+    let since = SystemTime::now() - Duration::from_secs(STATS_DAYS * 24 * 60 * 60);
+    let stats =
+        task::spawn_blocking(move || storage.usage_stats(guild_id, since, TOP_COUNT)).await??;
+
+    if stats == UsageStats::default() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("No cats sent in the last {} days", STATS_DAYS),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let top_items =
+        list(stats.top_items.iter().map(|(item, uses)| {
+            format!("{} ({})", shorten(item_name(item), MAX_NAME_LENGTH), uses)
+        }));
+    let top_users = list(
+        stats
+            .top_users
+            .iter()
+            .map(|(user_id, uses)| format!("<@{}> ({})", user_id, uses)),
+    );
+    let per_day = stats
+        .per_day
+        .iter()
+        .map(|(day, uses)| format!("{}: {}", day, uses))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    msg.channel_id
+        .send_message(&ctx.http, |m| {
+            m.embed(|e| {
+                e.title(format!("Stats for the last {} days", STATS_DAYS))
+                    .field("Top cats", shorten(&top_items, MAX_FIELD_LENGTH), true)
+                    .field("Top users", shorten(&top_users, MAX_FIELD_LENGTH), true)
+                    .field(
+                        "Requests per day",
+                        shorten(&per_day, MAX_FIELD_LENGTH),
+                        false,
+                    )
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Sends everything that has been served as a CSV file
+#[command("export")]
+#[owners_only]
+#[max_args(0)]
+async fn export_stats(ctx: &Context, msg: &Message) -> CommandResult {
+    let storage = storage(ctx).await?;
+    let csv = task::spawn_blocking(move || storage.export_served()).await??;
+
+    msg.channel_id
+        .send_files(
+            &ctx.http,
+            vec![AttachmentType::Bytes {
+                data: Cow::from(csv.into_bytes()),
+                filename: "taribot-stats.csv".to_owned(),
+            }],
+            |m| m,
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn storage(ctx: &Context) -> Result<Arc<Storage>, &'static str> {
+    ctx.data
+        .read()
+        .await
+        .get::<Storage>()
+        .cloned()
+        .ok_or("Failed to get Storage")
+}
+
+/// Numbered list, one entry per line
+fn list(entries: impl Iterator<Item = String>) -> String {
+    entries
+        .enumerate()
+        .map(|(i, entry)| format!("{}. {}", i + 1, entry))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `text` cut to at most `max` characters, with an ellipsis if anything was cut
+fn shorten(text: &str, max: usize) -> Cow<'_, str> {
+    if text.chars().count() <= max {
+        return Cow::Borrowed(text);
+    }
+    let cut = text.chars().take(max - 1).collect::<String>();
+    Cow::Owned(format!("{}…", cut))
+}
+
+/// File name of a served path or link, the rest is too long for the embed
+fn item_name(item: &str) -> &str {
+    item.rsplit(['/', '\\']).next().unwrap_or(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_items() {
+        assert_eq!(item_name("/cats/mittens/1.jpg"), "1.jpg");
+        assert_eq!(item_name("https://gfycat.com/FluffyCat"), "FluffyCat");
+        assert_eq!(item_name("cat.jpg"), "cat.jpg");
+    }
+
+    #[test]
+    fn shortens_long_text() {
+        assert_eq!(shorten("cat.jpg", 7), "cat.jpg");
+        assert_eq!(shorten("mittens.jpg", 7), "mitten…");
+        let name = "ä".repeat(255);
+        assert_eq!(
+            shorten(&name, MAX_NAME_LENGTH).chars().count(),
+            MAX_NAME_LENGTH
+        );
+    }
+}
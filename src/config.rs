@@ -0,0 +1,1190 @@
+use crate::media::shuffle::ShuffleScope;
+use crate::ratelimit::{Limit, RateLimits};
+use crate::secret::Secret;
+
+use serde::Deserialize;
+use serenity::{
+    model::id::{ChannelId, GuildId},
+    prelude::TypeMapKey,
+};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    thread,
+};
+
+/// Config file that's used when `CONFIG_PATH` isn't set, it's fine if it doesn't exist
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// The process environment from before `.env` was loaded into it
+static REAL_ENV: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Loads `.env` into the environment, variables that are already set keep their values.
+///
+/// The environment is saved first, so [`BotConfig::reload`] can tell the values that came
+/// from `.env` apart from the real ones.
+pub fn load_dotenv() -> dotenvy::Result<PathBuf> {
+    REAL_ENV.get_or_init(|| {
+        env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect()
+    });
+    dotenvy::dotenv()
+}
+
+/// Every problem found while loading the config
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings for the whole bot.
+///
+/// Loaded from a TOML file, with the environment variables taking precedence
+/// over the file. Guild sections override the defaults for that guild only.
+pub struct BotConfig {
+    pub token: Secret<String>,
+    pub prefix: String,
+    /// SQLite database for settings, history, caches and shuffle state
+    pub database_path: Option<PathBuf>,
+    pub shuffle_scope: ShuffleScope,
+    /// Seconds to wait for running commands to finish when shutting down
+    pub shutdown_timeout: u64,
+    /// `None` when the cat command is disabled
+    #[cfg_attr(not(feature = "cat"), allow(dead_code))]
+    pub cat: Option<CatSettings>,
+    /// `None` when the catvid command is disabled
+    #[cfg_attr(not(feature = "catvid"), allow(dead_code))]
+    pub catvid: Option<CatvidSettings>,
+    pub guilds: HashMap<GuildId, GuildSettings>,
+    /// Rate limits by command name
+    pub ratelimits: HashMap<String, RateLimits>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatSettings {
+    pub image_path: PathBuf,
+    pub max_images: u8,
+    /// Seconds between rescans of `image_path`
+    pub rescan_interval: u64,
+    /// Max total size of the attachments in a single message, in bytes
+    pub upload_limit: usize,
+    /// Max number of images processed at once, shared by all guilds
+    pub max_concurrent: usize,
+    /// Shared by all guilds
+    pub cache_path: Option<PathBuf>,
+}
+
+/// How the bot gets its Gfycat token
+#[derive(Clone, Debug, PartialEq)]
+pub enum GfycatLogin {
+    /// Logs in as the album owner with this password and reads the album from the account
+    Password(Secret<String>),
+    /// Only uses the client credentials, the album has to be public
+    ClientCredentials,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CatvidSettings {
+    Gfycat {
+        client_id: String,
+        client_secret: Secret<String>,
+        album_id: String,
+        /// Owner of the album
+        username: String,
+        login: GfycatLogin,
+        /// Where the Gfycat API is, `None` for the real one
+        api_url: Option<String>,
+        /// Clips larger than this many bytes are linked instead of uploaded
+        upload_limit: u64,
+    },
+    Local {
+        path: PathBuf,
+        /// Videos larger than this many bytes are skipped
+        upload_limit: u64,
+        rescan_interval: u64,
+    },
+    Urls {
+        file: PathBuf,
+    },
+}
+
+/// Settings of a single guild, with the defaults already filled in
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuildSettings {
+    pub prefix: String,
+    /// `None` when the cat command is disabled
+    pub cat: Option<CatSettings>,
+    /// Channels cat and catvid can be used in, any channel if it's empty
+    pub allowed_channels: Vec<ChannelId>,
+    /// Channels cat and catvid can't be used in
+    pub denied_channels: Vec<ChannelId>,
+}
+
+impl BotConfig {
+    /// Loads the config from `CONFIG_PATH` or `config.toml` and the environment
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_with(|name| env::var(name).ok())
+    }
+
+    /// Same as [`BotConfig::load`], but reads `.env` again first.
+    ///
+    /// Like on startup the real environment takes precedence over `.env`. The values
+    /// `.env` put into the environment on startup are ignored, so changing or removing
+    /// them in the file takes effect.
+    pub fn reload() -> Result<Self, ConfigError> {
+        let mut dotenv_vars = HashMap::new();
+        match dotenvy::dotenv_iter() {
+            Ok(iter) => {
+                for item in iter {
+                    let (name, value) = item.map_err(|e| {
+                        ConfigError(vec![format!("Failed to read .env file: {}", e)])
+                    })?;
+                    dotenv_vars.insert(name, value);
+                }
+            }
+            Err(e) if e.not_found() => (),
+            Err(e) => {
+                return Err(ConfigError(vec![format!(
+                    "Failed to read .env file: {}",
+                    e
+                )]))
+            }
+        }
+
+        Self::load_with(|name| layered_env(name, REAL_ENV.get(), &dotenv_vars))
+    }
+
+    fn load_with<E>(env: E) -> Result<Self, ConfigError>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let (path, required) = match env("CONFIG_PATH") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) if !required && !path.exists() => String::new(),
+            Err(e) => {
+                return Err(ConfigError(vec![format!(
+                    "Failed to read {}: {}",
+                    path.display(),
+                    e
+                )]))
+            }
+        };
+
+        Self::parse(&contents, env)
+    }
+
+    /// Parses `contents` as TOML and applies the overrides from `env` on top of it
+    pub fn parse<E>(contents: &str, env: E) -> Result<Self, ConfigError>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut errors = Vec::new();
+        let (mut raw, parsed) = match toml::from_str::<RawConfig>(contents) {
+            Ok(raw) => (raw, true),
+            Err(e) => {
+                errors.push(e.to_string());
+                (RawConfig::default(), false)
+            }
+        };
+
+        raw.apply_env(&env, &mut errors);
+        // Most settings would be reported missing without the file, so when it's
+        // broken only the environment is checked
+        let config = if parsed {
+            raw.validate(&mut errors)
+        } else {
+            None
+        };
+
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(ConfigError(errors)),
+        }
+    }
+
+    /// Whether cat and catvid can be used in the channel, the error is the reason for the user
+    #[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+    pub fn channel_allowed(
+        &self,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+    ) -> Result<(), String> {
+        let guild = match guild_id.and_then(|guild_id| self.guilds.get(&guild_id)) {
+            Some(guild) => guild,
+            None => return Ok(()),
+        };
+
+        let denied = guild.denied_channels.contains(&channel_id)
+            || !(guild.allowed_channels.is_empty() || guild.allowed_channels.contains(&channel_id));
+        if !denied {
+            return Ok(());
+        }
+
+        let mut reason = "Cats aren't allowed in this channel".to_owned();
+        if !guild.allowed_channels.is_empty() {
+            let channels = guild
+                .allowed_channels
+                .iter()
+                .map(|channel_id| format!("<#{}>", channel_id))
+                .collect::<Vec<_>>();
+            reason.push_str(&format!(", try {}", channels.join(", ")));
+        }
+        Err(reason)
+    }
+
+    /// Prefix to use in `guild_id`, or the default one outside guilds
+    pub fn prefix(&self, guild_id: Option<GuildId>) -> &str {
+        guild_id
+            .and_then(|guild_id| self.guilds.get(&guild_id))
+            .map(|guild| guild.prefix.as_str())
+            .unwrap_or(&self.prefix)
+    }
+}
+
+impl TypeMapKey for BotConfig {
+    type Value = Arc<BotConfig>;
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    token: Option<Secret<String>>,
+    prefix: Option<String>,
+    database_path: Option<PathBuf>,
+    shuffle_scope: Option<String>,
+    shutdown_timeout: Option<u64>,
+    cat: RawCat,
+    catvid: RawCatvid,
+    guilds: HashMap<String, RawGuild>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawCat {
+    enabled: Option<bool>,
+    image_path: Option<PathBuf>,
+    max_images: Option<u8>,
+    rescan_interval: Option<u64>,
+    upload_limit: Option<usize>,
+    max_concurrent: Option<usize>,
+    cache_path: Option<PathBuf>,
+    ratelimit: RawRateLimits,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawCatvid {
+    enabled: Option<bool>,
+    source: Option<String>,
+    path: Option<PathBuf>,
+    upload_limit: Option<u64>,
+    rescan_interval: Option<u64>,
+    url_file: Option<PathBuf>,
+    client_id: Option<String>,
+    client_secret: Option<Secret<String>>,
+    album_id: Option<String>,
+    grant: Option<String>,
+    username: Option<String>,
+    password: Option<Secret<String>>,
+    api_url: Option<String>,
+    ratelimit: RawRateLimits,
+}
+
+/// Limits in the `<count>/<seconds>` format
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawRateLimits {
+    user: Option<String>,
+    channel: Option<String>,
+    guild: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawGuild {
+    prefix: Option<String>,
+    allowed_channels: Vec<u64>,
+    denied_channels: Vec<u64>,
+    cat: RawGuildCat,
+}
+
+/// The cat settings a guild is allowed to override
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawGuildCat {
+    image_path: Option<PathBuf>,
+    max_images: Option<u8>,
+    rescan_interval: Option<u64>,
+    upload_limit: Option<usize>,
+}
+
+/// Replaces `field` with the value of the env var `name` if it's set
+fn env_override<E, T>(env: &E, name: &str, field: &mut Option<T>, errors: &mut Vec<String>)
+where
+    E: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = env(name) {
+        match value.parse() {
+            Ok(value) => *field = Some(value),
+            Err(e) => errors.push(format!("{}: {}", name, e)),
+        }
+    }
+}
+
+/// Like [`env_override`], but the secret can also be read from the file named in `<name>_FILE`,
+/// e.g. a Docker secret or a systemd credential
+fn env_secret<E>(env: &E, name: &str, field: &mut Option<Secret<String>>, errors: &mut Vec<String>)
+where
+    E: Fn(&str) -> Option<String>,
+{
+    let file_name = format!("{}_FILE", name);
+    match (env(name), env(&file_name)) {
+        (Some(_), Some(_)) => errors.push(format!(
+            "{} and {} are both set, use only one",
+            name, file_name
+        )),
+        (Some(value), None) => *field = Some(Secret::new(value)),
+        (None, Some(path)) => match fs::read_to_string(&path) {
+            // Editors like to end files with a newline
+            Ok(value) => {
+                *field = Some(Secret::new(value.trim_end_matches(['\r', '\n']).to_owned()))
+            }
+            Err(e) => errors.push(format!("{}: Failed to read {}: {}", file_name, path, e)),
+        },
+        (None, None) => (),
+    }
+}
+
+/// Reports `field` as missing if `value` is `None`
+fn required<T>(errors: &mut Vec<String>, value: Option<T>, field: &str, env: &str) -> Option<T> {
+    if value.is_none() {
+        missing(errors, field, env);
+    }
+    value
+}
+
+fn missing(errors: &mut Vec<String>, field: &str, env: &str) {
+    errors.push(format!(
+        "{} is missing, set it in the config or {}",
+        field, env
+    ));
+}
+
+impl RawConfig {
+    fn apply_env<E>(&mut self, env: &E, errors: &mut Vec<String>)
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        env_secret(env, "DISCORD_TOKEN", &mut self.token, errors);
+        env_override(env, "PREFIX", &mut self.prefix, errors);
+        env_override(env, "DATABASE_PATH", &mut self.database_path, errors);
+        env_override(env, "SHUFFLE_SCOPE", &mut self.shuffle_scope, errors);
+        env_override(env, "SHUTDOWN_TIMEOUT", &mut self.shutdown_timeout, errors);
+
+        env_override(env, "CAT_ENABLED", &mut self.cat.enabled, errors);
+        env_override(env, "CAT_IMAGE_PATH", &mut self.cat.image_path, errors);
+        env_override(env, "CAT_MAX_IMAGES", &mut self.cat.max_images, errors);
+        env_override(
+            env,
+            "CAT_RESCAN_INTERVAL",
+            &mut self.cat.rescan_interval,
+            errors,
+        );
+        env_override(env, "CAT_UPLOAD_LIMIT", &mut self.cat.upload_limit, errors);
+        env_override(
+            env,
+            "CAT_MAX_CONCURRENT",
+            &mut self.cat.max_concurrent,
+            errors,
+        );
+        env_override(env, "CAT_CACHE_PATH", &mut self.cat.cache_path, errors);
+        self.cat.ratelimit.apply_env(env, "CAT", errors);
+
+        let catvid = &mut self.catvid;
+        env_override(env, "CATVID_ENABLED", &mut catvid.enabled, errors);
+        env_override(env, "CATVID_SOURCE", &mut catvid.source, errors);
+        env_override(env, "CATVID_PATH", &mut catvid.path, errors);
+        env_override(env, "CATVID_UPLOAD_LIMIT", &mut catvid.upload_limit, errors);
+        env_override(
+            env,
+            "CATVID_RESCAN_INTERVAL",
+            &mut catvid.rescan_interval,
+            errors,
+        );
+        env_override(env, "CATVID_URL_FILE", &mut catvid.url_file, errors);
+        env_override(env, "CATVID_CLIENT_ID", &mut catvid.client_id, errors);
+        env_secret(
+            env,
+            "CATVID_CLIENT_SECRET",
+            &mut catvid.client_secret,
+            errors,
+        );
+        env_override(env, "CATVID_ALBUM_ID", &mut catvid.album_id, errors);
+        env_override(env, "CATVID_GRANT", &mut catvid.grant, errors);
+        env_override(env, "CATVID_USERNAME", &mut catvid.username, errors);
+        env_secret(env, "CATVID_PASSWORD", &mut catvid.password, errors);
+        env_override(env, "GFYCAT_API_URL", &mut catvid.api_url, errors);
+        catvid.ratelimit.apply_env(env, "CATVID", errors);
+    }
+
+    /// Checks every setting and fills in the defaults, returns `None` if something required is missing
+    fn validate(self, errors: &mut Vec<String>) -> Option<BotConfig> {
+        let token = self.token.filter(|token| !token.expose().is_empty());
+        if token.is_none() {
+            missing(errors, "token", "DISCORD_TOKEN");
+        }
+
+        let prefix = self.prefix.unwrap_or_else(|| ";".to_owned());
+        validate_prefix(errors, "prefix", &prefix);
+
+        let shuffle_scope = match self.shuffle_scope {
+            Some(scope) => scope.parse().unwrap_or_else(|e| {
+                errors.push(format!("shuffle_scope: {}", e));
+                ShuffleScope::Off
+            }),
+            None => ShuffleScope::Off,
+        };
+
+        let mut ratelimits = HashMap::new();
+        ratelimits.insert("cat".to_owned(), self.cat.ratelimit.validate(errors, "cat"));
+        ratelimits.insert(
+            "catvid".to_owned(),
+            self.catvid.ratelimit.validate(errors, "catvid"),
+        );
+
+        // Outer `Option` is whether it's enabled, inner one whether it's valid
+        let cat = match enabled(errors, "cat", self.cat.enabled, cfg!(feature = "cat")) {
+            true => Some(self.cat.validate(errors)),
+            false => None,
+        };
+        let catvid = match enabled(
+            errors,
+            "catvid",
+            self.catvid.enabled,
+            cfg!(feature = "catvid"),
+        ) {
+            true => Some(self.catvid.validate(errors)),
+            false => None,
+        };
+
+        // Sorted so the errors come in the same order every time
+        let mut raw_guilds = self.guilds.into_iter().collect::<Vec<_>>();
+        raw_guilds.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Every guild is checked even after one fails so all the problems are reported at once
+        let mut guilds = HashMap::new();
+        let mut guilds_valid = true;
+        for (id, guild) in raw_guilds {
+            let guild_id = match id.parse::<u64>() {
+                Ok(id) => GuildId(id),
+                Err(_) => {
+                    errors.push(format!("guilds.{}: guild id has to be a number", id));
+                    continue;
+                }
+            };
+            let default_cat = cat.as_ref().map(Option::as_ref);
+            match guild.validate(errors, &id, &prefix, default_cat) {
+                Some(guild) => {
+                    guilds.insert(guild_id, guild);
+                }
+                None => guilds_valid = false,
+            }
+        }
+        if !guilds_valid {
+            return None;
+        }
+
+        Some(BotConfig {
+            token: token?,
+            prefix,
+            database_path: self.database_path,
+            shuffle_scope,
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(30),
+            cat: cat.map(|cat| cat.ok_or(())).transpose().ok()?,
+            catvid: catvid.map(|catvid| catvid.ok_or(())).transpose().ok()?,
+            guilds,
+            ratelimits,
+        })
+    }
+}
+
+impl RawRateLimits {
+    fn apply_env<E>(&mut self, env: &E, command: &str, errors: &mut Vec<String>)
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let name = |scope: &str| format!("{}_RATELIMIT_{}", command, scope);
+        env_override(env, &name("USER"), &mut self.user, errors);
+        env_override(env, &name("CHANNEL"), &mut self.channel, errors);
+        env_override(env, &name("GUILD"), &mut self.guild, errors);
+    }
+
+    fn validate(&self, errors: &mut Vec<String>, section: &str) -> RateLimits {
+        let mut limit = |value: &Option<String>, scope: &str| {
+            value.as_ref().and_then(|value| {
+                value
+                    .parse::<Limit>()
+                    .map_err(|e| errors.push(format!("{}.ratelimit.{}: {}", section, scope, e)))
+                    .ok()
+            })
+        };
+
+        RateLimits {
+            user: limit(&self.user, "user"),
+            channel: limit(&self.channel, "channel"),
+            guild: limit(&self.guild, "guild"),
+        }
+    }
+}
+
+/// Whether a command is enabled, commands are enabled by default when they're compiled in
+fn enabled(errors: &mut Vec<String>, section: &str, enabled: Option<bool>, compiled: bool) -> bool {
+    match enabled {
+        Some(true) if !compiled => {
+            errors.push(format!(
+                "{0} is enabled but the bot was built without the {0} feature",
+                section
+            ));
+            false
+        }
+        Some(enabled) => enabled,
+        None => compiled,
+    }
+}
+
+fn validate_prefix(errors: &mut Vec<String>, field: &str, prefix: &str) {
+    if prefix.trim().is_empty() {
+        errors.push(format!("{} can't be empty", field));
+    }
+}
+
+fn validate_cat(errors: &mut Vec<String>, section: &str, cat: &CatSettings) {
+    if !cat.image_path.is_dir() {
+        errors.push(format!(
+            "{}.image_path ({}) is not a directory",
+            section,
+            cat.image_path.display()
+        ));
+    }
+    if cat.max_images < 1 {
+        errors.push(format!("{}.max_images has to be at least 1", section));
+    }
+    if cat.rescan_interval < 1 {
+        errors.push(format!("{}.rescan_interval has to be at least 1", section));
+    }
+    if cat.upload_limit < 1 {
+        errors.push(format!("{}.upload_limit has to be at least 1", section));
+    }
+    if cat.max_concurrent < 1 {
+        errors.push(format!("{}.max_concurrent has to be at least 1", section));
+    }
+}
+
+impl RawCat {
+    fn validate(self, errors: &mut Vec<String>) -> Option<CatSettings> {
+        let image_path = match self.image_path {
+            Some(path) => path,
+            None => {
+                missing(errors, "cat.image_path", "CAT_IMAGE_PATH");
+                return None;
+            }
+        };
+
+        let cat = CatSettings {
+            image_path,
+            max_images: self.max_images.unwrap_or(1),
+            rescan_interval: self.rescan_interval.unwrap_or(300),
+            upload_limit: self.upload_limit.unwrap_or(8_000_000),
+            max_concurrent: self.max_concurrent.unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|count| count.get())
+                    .unwrap_or(1)
+            }),
+            cache_path: self.cache_path,
+        };
+        validate_cat(errors, "cat", &cat);
+
+        Some(cat)
+    }
+}
+
+impl RawCatvid {
+    fn validate(self, errors: &mut Vec<String>) -> Option<CatvidSettings> {
+        let source = self.source.unwrap_or_else(|| "gfycat".to_owned());
+
+        match source.to_lowercase().as_str() {
+            "gfycat" => {
+                let client_id = required(
+                    errors,
+                    self.client_id,
+                    "catvid.client_id",
+                    "CATVID_CLIENT_ID",
+                );
+                let client_secret = required(
+                    errors,
+                    self.client_secret,
+                    "catvid.client_secret",
+                    "CATVID_CLIENT_SECRET",
+                );
+                let album_id =
+                    required(errors, self.album_id, "catvid.album_id", "CATVID_ALBUM_ID");
+                let username =
+                    required(errors, self.username, "catvid.username", "CATVID_USERNAME");
+
+                // Without a password only the public albums can be read
+                let grant = match self.grant {
+                    Some(grant) => grant.to_lowercase(),
+                    None if self.password.is_some() => "password".to_owned(),
+                    None => "client_credentials".to_owned(),
+                };
+                let login = match grant.as_str() {
+                    "password" => {
+                        required(errors, self.password, "catvid.password", "CATVID_PASSWORD")
+                            .map(GfycatLogin::Password)
+                    }
+                    "client_credentials" => Some(GfycatLogin::ClientCredentials),
+                    _ => {
+                        errors.push(format!(
+                            "catvid.grant has to be password or client_credentials, got {}",
+                            grant
+                        ));
+                        None
+                    }
+                };
+
+                let api_url = self.api_url.filter(|url| !url.is_empty());
+                if let Some(url) = &api_url {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        errors.push(format!(
+                            "catvid.api_url has to be an http or https URL, got {}",
+                            url
+                        ));
+                    }
+                }
+
+                Some(CatvidSettings::Gfycat {
+                    client_id: client_id?,
+                    client_secret: client_secret?,
+                    album_id: album_id?,
+                    username: username?,
+                    login: login?,
+                    api_url,
+                    upload_limit: self.upload_limit.unwrap_or(8_000_000),
+                })
+            }
+            "local" => {
+                let path = match self.path {
+                    Some(path) => path,
+                    None => {
+                        missing(errors, "catvid.path", "CATVID_PATH");
+                        return None;
+                    }
+                };
+                if !path.is_dir() {
+                    errors.push(format!(
+                        "catvid.path ({}) is not a directory",
+                        path.display()
+                    ));
+                }
+                let rescan_interval = self.rescan_interval.unwrap_or(300);
+                if rescan_interval < 1 {
+                    errors.push("catvid.rescan_interval has to be at least 1".to_owned());
+                }
+
+                Some(CatvidSettings::Local {
+                    path,
+                    upload_limit: self.upload_limit.unwrap_or(8_000_000),
+                    rescan_interval,
+                })
+            }
+            "urls" => match self.url_file {
+                Some(file) => Some(CatvidSettings::Urls { file }),
+                None => {
+                    missing(errors, "catvid.url_file", "CATVID_URL_FILE");
+                    None
+                }
+            },
+            _ => {
+                errors.push(format!(
+                    "catvid.source has to be gfycat, local or urls, got {}",
+                    source
+                ));
+                None
+            }
+        }
+    }
+}
+
+impl RawGuild {
+    /// `default_cat` is `None` when cat is disabled and `Some(None)` when its settings are invalid,
+    /// returns `None` in the latter case
+    fn validate(
+        self,
+        errors: &mut Vec<String>,
+        id: &str,
+        default_prefix: &str,
+        default_cat: Option<Option<&CatSettings>>,
+    ) -> Option<GuildSettings> {
+        let section = format!("guilds.{}", id);
+
+        let prefix = self.prefix.unwrap_or_else(|| default_prefix.to_owned());
+        validate_prefix(errors, &format!("{}.prefix", section), &prefix);
+
+        for channel_id in &self.allowed_channels {
+            if self.denied_channels.contains(channel_id) {
+                errors.push(format!(
+                    "{}: channel {} is both allowed and denied",
+                    section, channel_id
+                ));
+            }
+        }
+        let allowed_channels = self.allowed_channels.into_iter().map(ChannelId).collect();
+        let denied_channels = self.denied_channels.into_iter().map(ChannelId).collect();
+
+        let default_cat = match default_cat {
+            Some(default_cat) => default_cat?,
+            None => {
+                return Some(GuildSettings {
+                    prefix,
+                    cat: None,
+                    allowed_channels,
+                    denied_channels,
+                })
+            }
+        };
+
+        let cat = CatSettings {
+            image_path: self
+                .cat
+                .image_path
+                .unwrap_or_else(|| default_cat.image_path.clone()),
+            max_images: self.cat.max_images.unwrap_or(default_cat.max_images),
+            rescan_interval: self
+                .cat
+                .rescan_interval
+                .unwrap_or(default_cat.rescan_interval),
+            upload_limit: self.cat.upload_limit.unwrap_or(default_cat.upload_limit),
+            ..default_cat.clone()
+        };
+        if cat != *default_cat {
+            validate_cat(errors, &format!("{}.cat", section), &cat);
+        }
+
+        Some(GuildSettings {
+            prefix,
+            cat: Some(cat),
+            allowed_channels,
+            denied_channels,
+        })
+    }
+}
+
+/// Value of `name` from the real environment, or from `.env` if it's not set there.
+///
+/// Without a saved real environment everything that's set in the process counts as real.
+fn layered_env(
+    name: &str,
+    real_env: Option<&HashMap<String, String>>,
+    dotenv_vars: &HashMap<String, String>,
+) -> Option<String> {
+    let real = match real_env {
+        Some(real_env) => real_env.get(name).cloned(),
+        None => env::var(name).ok(),
+    };
+    real.or_else(|| dotenv_vars.get(name).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[cfg(all(feature = "cat", feature = "catvid"))]
+    fn temp_dir() -> String {
+        std::env::temp_dir().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    #[cfg(all(feature = "cat", feature = "catvid"))]
+    fn parses_file() {
+        let contents = format!(
+            r#"
+            token = "token"
+            shuffle_scope = "guild"
+
+            [cat]
+            image_path = "{0}"
+            max_images = 5
+
+            [cat.ratelimit]
+            user = "3/60"
+
+            [catvid]
+            source = "urls"
+            url_file = "videos.txt"
+
+            [guilds.123]
+            prefix = "!"
+
+            [guilds.123.cat]
+            max_images = 2
+            "#,
+            temp_dir()
+        );
+
+        let config = BotConfig::parse(&contents, no_env).unwrap();
+        assert_eq!(config.token.expose(), "token");
+        assert_eq!(config.prefix, ";");
+        assert_eq!(config.shuffle_scope, ShuffleScope::Guild);
+        assert_eq!(config.shutdown_timeout, 30);
+        let cat = config.cat.as_ref().unwrap();
+        assert_eq!(cat.max_images, 5);
+        assert_eq!(cat.upload_limit, 8_000_000);
+        assert_eq!(
+            config.catvid,
+            Some(CatvidSettings::Urls {
+                file: PathBuf::from("videos.txt")
+            })
+        );
+
+        let guild = &config.guilds[&GuildId(123)];
+        assert_eq!(guild.prefix, "!");
+        let guild_cat = guild.cat.as_ref().unwrap();
+        assert_eq!(guild_cat.max_images, 2);
+        assert_eq!(guild_cat.image_path, cat.image_path);
+        assert_eq!(config.prefix(Some(GuildId(123))), "!");
+        assert_eq!(config.prefix(Some(GuildId(1))), ";");
+        assert_eq!(config.prefix(None), ";");
+
+        let limits = &config.ratelimits["cat"];
+        assert_eq!(limits.user, Some("3/60".parse().unwrap()));
+        assert_eq!(limits.channel, None);
+        assert_eq!(config.ratelimits["catvid"], RateLimits::default());
+    }
+
+    #[test]
+    #[cfg(all(feature = "cat", feature = "catvid"))]
+    fn env_overrides_file() {
+        let contents = r#"
+            token = "file"
+            [cat]
+            image_path = "/does/not/exist"
+            max_images = 5
+        "#;
+        let dir = temp_dir();
+        let env = |name: &str| match name {
+            "DISCORD_TOKEN" => Some("env".to_owned()),
+            "CAT_IMAGE_PATH" => Some(dir.clone()),
+            "CATVID_SOURCE" => Some("urls".to_owned()),
+            "CATVID_URL_FILE" => Some("videos.txt".to_owned()),
+            _ => None,
+        };
+
+        let config = BotConfig::parse(contents, env).unwrap();
+        assert_eq!(config.token.expose(), "env");
+        let cat = config.cat.unwrap();
+        assert_eq!(cat.image_path, PathBuf::from(temp_dir()));
+        assert_eq!(cat.max_images, 5);
+    }
+
+    #[test]
+    #[cfg(all(feature = "cat", feature = "catvid"))]
+    fn reports_every_problem() {
+        let contents = r#"
+            shuffle_scope = "sometimes"
+            [cat]
+            image_path = "/does/not/exist"
+            max_images = 0
+            [catvid]
+            source = "local"
+            [guilds.abc]
+            prefix = "!"
+        "#;
+        let env = |name: &str| match name {
+            "CAT_UPLOAD_LIMIT" => Some("lots".to_owned()),
+            _ => None,
+        };
+
+        let errors = BotConfig::parse(contents, env).err().unwrap().0;
+        assert_eq!(errors.len(), 7, "{:?}", errors);
+        for expected in [
+            "CAT_UPLOAD_LIMIT",
+            "token",
+            "shuffle_scope",
+            "cat.image_path",
+            "cat.max_images",
+            "catvid.path",
+            "guilds.abc",
+        ] {
+            assert!(
+                errors.iter().any(|error| error.contains(expected)),
+                "{} not in {:?}",
+                expected,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_env_errors() {
+        let contents = r#"
+            token = "token
+        "#;
+        let env = |name: &str| match name {
+            "SHUTDOWN_TIMEOUT" => Some("soon".to_owned()),
+            _ => None,
+        };
+
+        let errors = BotConfig::parse(contents, env).err().unwrap().0;
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[1].contains("SHUTDOWN_TIMEOUT"), "{:?}", errors);
+    }
+
+    #[test]
+    #[cfg(feature = "catvid")]
+    fn picks_gfycat_grant() {
+        let contents = r#"
+            token = "token"
+            [cat]
+            enabled = false
+            [catvid]
+            client_id = "id"
+            client_secret = "secret"
+            album_id = "album"
+            username = "user"
+        "#;
+        let login = |env: fn(&str) -> Option<String>| match BotConfig::parse(contents, env)
+            .map(|config| config.catvid)
+        {
+            Ok(Some(CatvidSettings::Gfycat { login, .. })) => Ok(login),
+            Ok(other) => panic!("Expected gfycat settings, got {:?}", other),
+            Err(e) => Err(e.0),
+        };
+
+        assert_eq!(login(no_env), Ok(GfycatLogin::ClientCredentials));
+        assert_eq!(
+            login(|name| (name == "CATVID_PASSWORD").then(|| "pass".to_owned())),
+            Ok(GfycatLogin::Password(Secret::new("pass".to_owned())))
+        );
+        assert!(login(|name| (name == "CATVID_GRANT").then(|| "password".to_owned())).is_err());
+        assert!(login(|name| (name == "CATVID_GRANT").then(|| "magic".to_owned())).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "catvid")]
+    fn reads_gfycat_api_url() {
+        let contents = r#"
+            token = "token"
+            [cat]
+            enabled = false
+            [catvid]
+            client_id = "id"
+            client_secret = "secret"
+            album_id = "album"
+            username = "user"
+            api_url = "https://proxy.example.com/gfycat"
+        "#;
+        let api_url = |env: fn(&str) -> Option<String>| match BotConfig::parse(contents, env)
+            .map(|config| config.catvid)
+        {
+            Ok(Some(CatvidSettings::Gfycat { api_url, .. })) => Ok(api_url),
+            Ok(other) => panic!("Expected gfycat settings, got {:?}", other),
+            Err(e) => Err(e.0),
+        };
+
+        assert_eq!(
+            api_url(no_env),
+            Ok(Some("https://proxy.example.com/gfycat".to_owned()))
+        );
+        assert_eq!(
+            api_url(|name| (name == "GFYCAT_API_URL").then(|| "http://localhost:1234".to_owned())),
+            Ok(Some("http://localhost:1234".to_owned()))
+        );
+        assert!(
+            api_url(|name| (name == "GFYCAT_API_URL").then(|| "localhost".to_owned())).is_err()
+        );
+    }
+
+    #[test]
+    fn reads_secret_files() {
+        let path = std::env::temp_dir().join(format!("taribot-{}-token", std::process::id()));
+        fs::write(&path, "from file\n").unwrap();
+        let contents = r#"
+            [cat]
+            enabled = false
+            [catvid]
+            enabled = false
+        "#;
+        let file = path.to_string_lossy().into_owned();
+
+        let config = BotConfig::parse(contents, |name: &str| match name {
+            "DISCORD_TOKEN_FILE" => Some(file.clone()),
+            _ => None,
+        });
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().token.expose(), "from file");
+
+        let errors = BotConfig::parse(contents, |name: &str| match name {
+            "DISCORD_TOKEN" | "DISCORD_TOKEN_FILE" => Some(file.clone()),
+            _ => None,
+        })
+        .err()
+        .unwrap()
+        .0;
+        assert!(errors[0].contains("are both set"), "{:?}", errors);
+
+        // The file is gone by now
+        let errors = BotConfig::parse(contents, |name: &str| match name {
+            "DISCORD_TOKEN_FILE" => Some(file.clone()),
+            _ => None,
+        })
+        .err()
+        .unwrap()
+        .0;
+        assert!(errors[0].starts_with("DISCORD_TOKEN_FILE"), "{:?}", errors);
+    }
+
+    #[test]
+    fn disabled_commands_need_no_settings() {
+        let contents = r#"
+            token = "token"
+            [catvid]
+            enabled = false
+            [guilds.123]
+            prefix = "!"
+        "#;
+        let env = |name: &str| match name {
+            "CAT_ENABLED" => Some("false".to_owned()),
+            _ => None,
+        };
+
+        let config = BotConfig::parse(contents, env).unwrap();
+        assert!(config.cat.is_none());
+        assert!(config.catvid.is_none());
+        assert_eq!(config.guilds[&GuildId(123)].prefix, "!");
+        assert!(config.guilds[&GuildId(123)].cat.is_none());
+    }
+
+    #[test]
+    fn channel_lists() {
+        let contents = r#"
+            token = "token"
+            [cat]
+            enabled = false
+            [catvid]
+            enabled = false
+            [guilds.1]
+            allowed_channels = [10, 11]
+            denied_channels = [11]
+            [guilds.2]
+            denied_channels = [20]
+        "#;
+        let errors = BotConfig::parse(contents, no_env).err().unwrap().0;
+        assert_eq!(
+            errors,
+            vec!["guilds.1: channel 11 is both allowed and denied"]
+        );
+
+        let config = BotConfig::parse(&contents.replace("[11]", "[]"), no_env).unwrap();
+        let allowed = |guild_id, channel_id| {
+            config
+                .channel_allowed(Some(GuildId(guild_id)), ChannelId(channel_id))
+                .is_ok()
+        };
+        assert!(allowed(1, 10));
+        assert!(allowed(1, 11));
+        assert!(!allowed(1, 12));
+        assert_eq!(
+            config.channel_allowed(Some(GuildId(1)), ChannelId(12)),
+            Err("Cats aren't allowed in this channel, try <#10>, <#11>".to_owned())
+        );
+        assert!(!allowed(2, 20));
+        assert!(allowed(2, 21));
+        assert!(allowed(3, 30));
+        assert!(config.channel_allowed(None, ChannelId(20)).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let contents = r#"
+            [guilds.123.cat]
+            max_concurrent = 2
+        "#;
+
+        assert!(BotConfig::parse(contents, no_env).is_err());
+    }
+
+    #[test]
+    fn prefers_real_env_over_dotenv() {
+        let real_env = HashMap::from([("CAT_ENABLED".to_owned(), "false".to_owned())]);
+        let dotenv_vars = HashMap::from([
+            ("CAT_ENABLED".to_owned(), "true".to_owned()),
+            ("CATVID_SOURCE".to_owned(), "urls".to_owned()),
+        ]);
+        let env = |name| layered_env(name, Some(&real_env), &dotenv_vars);
+
+        assert_eq!(env("CAT_ENABLED").as_deref(), Some("false"));
+        assert_eq!(env("CATVID_SOURCE").as_deref(), Some("urls"));
+        // Removed from .env, even though startup put it into the process environment
+        assert_eq!(env("CATVID_UPLOAD_LIMIT"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "cat")]
+    fn reports_every_broken_guild() {
+        let contents = r#"
+            token = "token"
+            [cat]
+            image_path = "/does/not/exist"
+            [catvid]
+            enabled = false
+            [guilds.1]
+            prefix = " "
+            [guilds.2]
+            allowed_channels = [3]
+            denied_channels = [3]
+        "#;
+
+        let errors = BotConfig::parse(contents, no_env).err().unwrap().0;
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.starts_with("guilds.1.prefix")),
+            "{:?}",
+            errors
+        );
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.contains("channel 3 is both allowed and denied")),
+            "{:?}",
+            errors
+        );
+    }
+}
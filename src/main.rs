@@ -1,7 +1,19 @@
+#[cfg(feature = "catvid")]
 mod api;
+#[cfg(any(feature = "cat", feature = "catvid"))]
+mod channels;
 mod commands;
+mod config;
+mod media;
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+mod ratelimit;
+mod reload;
+mod secret;
+mod shutdown;
+mod storage;
+#[cfg(feature = "catvid")]
+mod video;
 
-use dotenv::dotenv;
 use serenity::{
     async_trait,
     framework::{
@@ -11,32 +23,75 @@ use serenity::{
             macros::{group, help, hook},
             CommandResult, DispatchError,
         },
-        standard::{Args, CommandGroup, HelpOptions, Reason},
+        standard::{Args, Command, CommandGroup, GroupOptions, HelpOptions, Reason},
         StandardFramework,
     },
     http::Http,
-    model::{channel::Message, event::ResumedEvent, gateway::Ready, id::UserId},
+    model::{
+        application::interaction::Interaction, channel::Message, event::ResumedEvent,
+        gateway::Ready, id::UserId,
+    },
     prelude::*,
 };
-use std::{collections::HashSet, env, sync::Arc};
+use std::{
+    collections::HashSet,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
+#[cfg(feature = "cat")]
 use commands::cat::*;
+#[cfg(feature = "catvid")]
 use commands::catvid::*;
+use commands::prefix::*;
+use commands::reload::*;
+use commands::slash;
+use commands::stats::*;
+use config::{load_dotenv, BotConfig};
+use media::shuffle::{spawn_flush, Shuffler};
+use ratelimit::RateLimiter;
+use reload::CommandConfigs;
+use shutdown::InFlight;
+use storage::{record_use, CommandUse, Storage};
 
 use tracing::{error, info, warn};
 
+#[cfg(feature = "catvid")]
 struct CatvidConfigContainer;
 
+#[cfg(feature = "catvid")]
 impl TypeMapKey for CatvidConfigContainer {
-    type Value = Arc<Mutex<CatvidConfig>>;
+    type Value = Arc<CatvidConfig>;
 }
 
-struct Handler;
+#[derive(Default)]
+struct Handler {
+    /// Ready comes again on every reconnect, the slash commands only need registering once
+    slash_registered: AtomicBool,
+}
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("Connected as {}", ready.user.name);
+
+        if !self.slash_registered.swap(true, Ordering::Relaxed) {
+            if let Err(e) = slash::register(&ctx.data, &ctx.http).await {
+                error!("Failed to register slash commands: {}", e);
+                // Try again on the next ready
+                self.slash_registered.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::ApplicationCommand(command) = interaction {
+            slash::dispatch(&ctx, &command).await;
+        }
     }
 
     async fn resume(&self, _: Context, _: ResumedEvent) {
@@ -92,6 +147,48 @@ async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, _com
     }
 }
 
+#[hook]
+async fn before(ctx: &Context, _msg: &Message, command_name: &str) -> bool {
+    let started = match ctx.data.read().await.get::<InFlight>() {
+        Some(in_flight) => in_flight.begin(),
+        None => true,
+    };
+    if !started {
+        info!("Shutting down, ignoring {}", command_name);
+    }
+
+    started
+}
+
+#[hook]
+async fn after(ctx: &Context, msg: &Message, command_name: &str, result: CommandResult) {
+    if let Err(e) = &result {
+        error!("Command {} failed: {}", command_name, e);
+    }
+
+    record_use(
+        ctx,
+        CommandUse {
+            command: command_name.to_owned(),
+            guild_id: msg.guild_id,
+            channel_id: msg.channel_id,
+            user_id: msg.author.id,
+            used_at: SystemTime::now(),
+            success: result.is_ok(),
+        },
+    )
+    .await;
+
+    if let Some(in_flight) = ctx.data.read().await.get::<InFlight>() {
+        in_flight.end();
+    }
+}
+
+#[hook]
+async fn dynamic_prefix(ctx: &Context, msg: &Message) -> Option<String> {
+    prefix(&*ctx.data.read().await, msg.guild_id)
+}
+
 #[help]
 #[max_levenshtein_distance(3)]
 async fn help(
@@ -106,13 +203,60 @@ async fn help(
     Ok(())
 }
 
+// Commands are added in `general_group` depending on what's enabled
 #[group]
-#[commands(cat, catvid)]
 struct General;
 
+/// Commands that are compiled in and enabled in the config
+#[cfg_attr(
+    not(any(feature = "cat", feature = "catvid")),
+    allow(unused_mut, unused_variables)
+)]
+fn enabled_commands(config: &BotConfig) -> Vec<&'static Command> {
+    let mut commands: Vec<&'static Command> = vec![&RELOAD_CONFIG_COMMAND];
+
+    #[cfg(feature = "cat")]
+    if config.cat.is_some() {
+        commands.push(&CAT_COMMAND);
+    }
+    #[cfg(feature = "catvid")]
+    if config.catvid.is_some() {
+        commands.push(&CATVID_COMMAND);
+    }
+
+    commands
+}
+
+/// `General` group with only the given commands in it.
+///
+/// The framework needs groups to live forever, so this is leaked. It's only called once on startup.
+fn general_group(commands: Vec<&'static Command>) -> &'static CommandGroup {
+    let base = GENERAL_GROUP.options;
+    let options = GroupOptions {
+        prefixes: base.prefixes,
+        only_in: base.only_in,
+        owners_only: base.owners_only,
+        owner_privilege: base.owner_privilege,
+        help_available: base.help_available,
+        allowed_roles: base.allowed_roles,
+        required_permissions: base.required_permissions,
+        checks: base.checks,
+        default_command: base.default_command,
+        description: base.description,
+        summary: base.summary,
+        commands: Box::leak(commands.into_boxed_slice()),
+        sub_groups: base.sub_groups,
+    };
+
+    Box::leak(Box::new(CommandGroup {
+        name: GENERAL_GROUP.name,
+        options: Box::leak(Box::new(options)),
+    }))
+}
+
 #[tokio::main]
 async fn main() {
-    if dotenv().is_err() {
+    if load_dotenv().is_err() {
         warn!("Failed to load .env file");
     }
 
@@ -122,12 +266,50 @@ async fn main() {
     // `RUST_LOG` to debug`.
     tracing_subscriber::fmt::init();
 
-    let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
+    let config = match BotConfig::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let http = Http::new(&token);
+    let storage = match &config.database_path {
+        Some(path) => Storage::open(path),
+        None => {
+            warn!("Database path not set, prefixes, history, caches and shuffle state won't survive restarts");
+            Storage::in_memory()
+        }
+    };
+    let storage = match storage {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            error!("Failed to open the database: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Scans the media folders and reads the caches from the database
+    let command_configs = {
+        let (config, storage) = (config.clone(), storage.clone());
+        tokio::task::spawn_blocking(move || CommandConfigs::new(&config, &storage))
+            .await
+            .expect("Failed to set up the commands")
+    };
+    let command_configs = command_configs.unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    let shuffler = Arc::new(
+        Shuffler::new(config.shuffle_scope, storage.clone()).expect("Failed to load shuffle state"),
+    );
+    spawn_flush(shuffler.clone(), Duration::from_secs(60));
+
+    let http = Http::new(config.token.expose());
 
     // We will fetch your bot's owners and id
-    let (owners, _bot_id) = match http.get_current_application_info().await {
+    let (owners, _app_id) = match http.get_current_application_info().await {
         Ok(info) => {
             let mut owners = HashSet::new();
             owners.insert(info.owner.id);
@@ -136,43 +318,63 @@ async fn main() {
         }
         Err(why) => panic!("Could not access application info: {:?}", why),
     };
+    let bot_id = match http.get_current_user().await {
+        Ok(user) => user.id,
+        Err(why) => panic!("Could not access the bot user: {:?}", why),
+    };
+
+    let prefixes = Arc::new(PrefixStore::new(storage.clone()).expect("Failed to load prefixes"));
 
+    // Prefixes come from `dynamic_prefix`, so they can differ per guild.
+    // Mentioning the bot always works in case someone forgets the prefix.
     let framework = StandardFramework::new()
         .configure(|c| {
-            c.owners(owners).prefix(
-                env::var("PREFIX")
-                    .expect("Expected a prefix in the environment")
-                    .as_str(),
-            )
+            c.owners(owners.clone())
+                .on_mention(Some(bot_id))
+                .prefixes(Vec::<&str>::new())
+                .dynamic_prefix(dynamic_prefix)
         })
         .help(&HELP)
-        .group(&GENERAL_GROUP)
-        .on_dispatch_error(dispatch_error);
+        .on_dispatch_error(dispatch_error)
+        .before(before)
+        .after(after);
+
+    let commands = enabled_commands(&config);
+    if commands.len() == 1 {
+        warn!("All commands are disabled");
+    }
+    let framework = framework
+        .group(general_group(commands))
+        .group(&PREFIX_GROUP)
+        .group(&STATS_GROUP);
 
-    let intents = GatewayIntents::GUILD_MESSAGES
+    // Guilds are needed in the cache to check the permissions of the prefix command
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
 
-    let mut client = Client::builder(&token, intents)
+    let mut client = Client::builder(config.token.expose(), intents)
         .framework(framework)
-        .event_handler(Handler)
+        .event_handler(Handler::default())
         .await
         .expect("Err creating client");
 
     {
         let mut data = client.data.write().await;
-        data.insert::<CatConfig>(CatConfig::new());
-        data.insert::<CatvidConfigContainer>(Arc::new(Mutex::new(CatvidConfig::new())));
+        data.insert::<BotConfig>(config.clone());
+        data.insert::<Storage>(storage);
+        data.insert::<PrefixStore>(prefixes);
+        data.insert::<RateLimiter>(RateLimiter::new(owners, config.ratelimits.clone()));
+        command_configs.insert(&mut data);
+        data.insert::<Shuffler>(shuffler);
+        data.insert::<InFlight>(Arc::new(InFlight::new()));
     }
 
-    let shard_manager = client.shard_manager.clone();
+    #[cfg(unix)]
+    reload::spawn_hangup_reload(client.data.clone(), client.cache_and_http.http.clone());
 
-    tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Could not register ctrl+c handler");
-        shard_manager.lock().await.shutdown_all().await;
-    });
+    shutdown::spawn_signal_shutdown(client.data.clone(), client.shard_manager.clone());
 
     if let Err(why) = client.start().await {
         error!("Client error: {:?}", why);
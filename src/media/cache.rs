@@ -0,0 +1,273 @@
+use super::encode::{attachment_name, encode, EncodeError, EncodedImage};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Semaphore, task};
+use tracing::{info, warn};
+
+/// Extensions the encoder can produce that are worth caching, animated GIFs are sent as is
+const CACHED_EXTENSIONS: &[&str] = &["jpg", "png"];
+
+/// Makes the temporary file of every write unique
+static NEXT_WRITE: AtomicU64 = AtomicU64::new(0);
+
+/// Disk cache of already encoded images.
+///
+/// Entries are keyed by the source path, its modification time and size, and the
+/// byte budget it was encoded for, so a changed source simply stops matching its old entry.
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(ThumbnailCache { dir })
+    }
+
+    /// Returns the cached encoding of `source`, if there is an up to date one
+    pub fn get(&self, source: &Path, budget: usize) -> Option<EncodedImage> {
+        let key = key(source, budget).ok()?;
+
+        CACHED_EXTENSIONS.iter().find_map(|extension| {
+            let data = fs::read(self.dir.join(format!("{}.{}", key, extension))).ok()?;
+            Some(EncodedImage {
+                data,
+                filename: attachment_name(source, extension),
+            })
+        })
+    }
+
+    /// Stores the encoded image, does nothing for formats that aren't cached
+    pub fn put(&self, source: &Path, budget: usize, image: &EncodedImage) -> io::Result<()> {
+        let extension = match Path::new(&image.filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some(extension) if CACHED_EXTENSIONS.contains(&extension) => extension,
+            _ => return Ok(()),
+        };
+
+        let key = key(source, budget)?;
+        // Write to a temporary file first so readers never see a partial image. Warm-up and
+        // commands can encode the same image at once, so each write gets its own file.
+        let temp = self.dir.join(format!(
+            "{}.{}-{}.tmp",
+            key,
+            process::id(),
+            NEXT_WRITE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, &image.data)?;
+        fs::rename(temp, self.dir.join(format!("{}.{}", key, extension)))
+    }
+
+    /// Encodes `source`, reusing and filling the cache
+    pub fn encode(&self, source: &Path, budget: usize) -> Result<EncodedImage, EncodeError> {
+        if let Some(image) = self.get(source, budget) {
+            return Ok(image);
+        }
+
+        let image = encode(source, budget)?;
+        if let Err(e) = self.put(source, budget, &image) {
+            warn!("Failed to cache {:?}: {}", source, e);
+        }
+
+        Ok(image)
+    }
+
+    /// Removes every entry last written before `older_than` that doesn't belong to one of the
+    /// source and budget pairs, returns the number removed.
+    ///
+    /// Newer entries are left alone since they can be for images added after `sources` was
+    /// listed, and so are newer temporary files since their writes can still be going on.
+    /// Older ones are left behind by writes that never finished and are removed.
+    pub fn prune(&self, sources: &[(PathBuf, usize)], older_than: SystemTime) -> io::Result<usize> {
+        let keys = sources
+            .iter()
+            .filter_map(|(source, budget)| key(source, *budget).ok())
+            .collect::<HashSet<_>>();
+
+        let mut removed = 0;
+        for entry in self.dir.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+            let old = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified < older_than);
+            let temp = path.extension().is_some_and(|extension| extension == "tmp");
+            let stale = temp
+                || match path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(stem) => !keys.contains(stem),
+                    None => true,
+                };
+            let stale = old && stale;
+            if stale && fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Fills the cache for all source and budget pairs in the background, then prunes the stale entries
+/// that were there before it started.
+///
+/// Takes one permit from `limiter` at a time so it doesn't slow down commands much.
+/// Stops early if the cache is dropped, so a replaced config doesn't prune the entries of the new one.
+pub fn spawn_warm(
+    cache: Arc<ThumbnailCache>,
+    sources: Vec<(PathBuf, usize)>,
+    limiter: Arc<Semaphore>,
+) {
+    let cache = Arc::downgrade(&cache);
+    let started = SystemTime::now();
+
+    tokio::spawn(async move {
+        let sources = Arc::new(sources);
+
+        for index in 0..sources.len() {
+            let _permit = limiter.acquire().await;
+            let cache = match cache.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            let sources = sources.clone();
+            let result = task::spawn_blocking(move || {
+                let (source, budget) = &sources[index];
+                let (source, budget) = (source.as_path(), *budget);
+                if cache.get(source, budget).is_none() {
+                    if let Err(e) = cache.encode(source, budget) {
+                        warn!("Failed to cache {:?}: {}", source, e);
+                    }
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                warn!("Thumbnail cache task failed: {}", e);
+            }
+        }
+
+        let cache = match cache.upgrade() {
+            Some(cache) => cache,
+            None => return,
+        };
+        let count = sources.len();
+        match task::spawn_blocking(move || cache.prune(&sources, started)).await {
+            Ok(Ok(removed)) => info!(
+                "Thumbnail cache warmed for {} images, removed {} stale entries",
+                count, removed
+            ),
+            Ok(Err(e)) => warn!("Failed to prune thumbnail cache: {}", e),
+            Err(e) => warn!("Thumbnail cache task failed: {}", e),
+        }
+    });
+}
+
+/// Stable FNV-1a hash of the source path, modification time, size and budget
+fn key(source: &Path, budget: usize) -> io::Result<String> {
+    let metadata = fs::metadata(source)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0);
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let parts = [
+        source.to_string_lossy().as_bytes().to_vec(),
+        modified.to_le_bytes().to_vec(),
+        metadata.len().to_le_bytes().to_vec(),
+        (budget as u64).to_le_bytes().to_vec(),
+    ];
+    for byte in parts.iter().flatten() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("taribot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn caches_and_invalidates() {
+        let dir = temp_dir("cache");
+        let source = dir.join("cat.png");
+        RgbImage::from_pixel(4, 4, Rgb([255, 0, 0]))
+            .save(&source)
+            .unwrap();
+        let cache = ThumbnailCache::new(dir.join("cache")).unwrap();
+
+        assert!(cache.get(&source, 1000).is_none());
+        let encoded = cache.encode(&source, 1000).unwrap();
+        let cached = cache.get(&source, 1000).unwrap();
+        assert_eq!(cached.data, encoded.data);
+        assert_eq!(cached.filename, "cat.jpg");
+        // Different budget is a different entry
+        assert!(cache.get(&source, 2000).is_none());
+
+        // Changing the source changes its size, so the old entry no longer matches
+        RgbImage::from_pixel(8, 8, Rgb([0, 255, 0]))
+            .save(&source)
+            .unwrap();
+        assert!(cache.get(&source, 1000).is_none());
+        assert_eq!(
+            cache
+                .prune(
+                    &[(source.clone(), 1000)],
+                    SystemTime::now() + Duration::from_secs(60)
+                )
+                .unwrap(),
+            1
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prune_keeps_new_entries_and_writes() {
+        let dir = temp_dir("prune");
+        let source = dir.join("cat.png");
+        RgbImage::from_pixel(4, 4, Rgb([255, 0, 0]))
+            .save(&source)
+            .unwrap();
+        let cache = ThumbnailCache::new(dir.join("cache")).unwrap();
+        cache.encode(&source, 1000).unwrap();
+        let writing = dir.join("cache").join("0123456789abcdef.1-1.tmp");
+        fs::write(&writing, b"partial").unwrap();
+
+        // Written after warm-up started, e.g. for an image that was added since
+        assert_eq!(cache.prune(&[], UNIX_EPOCH).unwrap(), 0);
+        assert!(cache.get(&source, 1000).is_some());
+        assert!(writing.exists());
+
+        // Older temporary files are from writes that didn't finish
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(cache.prune(&[], later).unwrap(), 2);
+        assert!(cache.get(&source, 1000).is_none());
+        assert!(!writing.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
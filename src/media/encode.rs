@@ -0,0 +1,331 @@
+use super::cache::ThumbnailCache;
+use image::{
+    codecs::gif::GifDecoder, AnimationDecoder, DynamicImage, ImageError, ImageOutputFormat,
+};
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Cursor},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task};
+use tracing::{debug, error};
+
+/// Largest width or height an image is scaled to, each step is tried until the image fits the budget
+const DIMENSION_STEPS: &[u32] = &[1920, 1600, 1280, 1024, 800];
+/// JPEG qualities that are tried for every dimension step before moving on to the next one
+const QUALITY_STEPS: &[u8] = &[100, 90, 80, 70, 60];
+
+#[derive(Debug)]
+pub enum EncodeError {
+    /// Source file couldn't be read
+    Unreadable(io::Error),
+    /// Source file isn't a valid image
+    Decode(ImageError),
+    /// Writing the scaled image failed
+    Encode(ImageError),
+    /// Image didn't fit the budget even at the smallest size, contains the smallest size reached
+    TooLarge(usize),
+    /// Encoding panicked or was cancelled
+    Task(task::JoinError),
+}
+
+impl Error for EncodeError {}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::Unreadable(error) => write!(f, "Failed to read file: {}", error),
+            EncodeError::Decode(error) => write!(f, "Failed to decode image: {}", error),
+            EncodeError::Encode(error) => write!(f, "Failed to encode image: {}", error),
+            EncodeError::TooLarge(size) => {
+                write!(f, "Image is still {} bytes at the smallest size", size)
+            }
+            EncodeError::Task(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(error: io::Error) -> Self {
+        Self::Unreadable(error)
+    }
+}
+
+impl From<ImageError> for EncodeError {
+    /// Errors from reading images, the ones from writing are mapped to [`EncodeError::Encode`] separately
+    fn from(error: ImageError) -> Self {
+        match error {
+            ImageError::IoError(error) => Self::Unreadable(error),
+            error => Self::Decode(error),
+        }
+    }
+}
+
+/// Image that's ready to be sent as an attachment
+pub struct EncodedImage {
+    pub data: Vec<u8>,
+    pub filename: String,
+}
+
+/// Reads the image from `path` and encodes it so it's at most `budget` bytes.
+///
+/// JPEG quality is lowered first and then the resolution, until the image fits.
+/// Animated GIFs are sent as they are since re-encoding would drop the animation,
+/// unless they're over the budget in which case only the first frame is sent.
+/// Images with transparent pixels are encoded as PNG, everything else as JPEG.
+pub fn encode(path: &Path, budget: usize) -> Result<EncodedImage, EncodeError> {
+    if is_gif(path) && is_animated_gif(path)? {
+        let data = fs::read(path)?;
+        if data.len() <= budget {
+            return Ok(EncodedImage {
+                data,
+                filename: attachment_name(path, "gif"),
+            });
+        }
+        debug!(
+            "Animated GIF {:?} is over the budget, sending first frame",
+            path
+        );
+    }
+
+    let image = image::open(path)?;
+    let transparent = has_transparency(&image);
+    // JPEG has no alpha channel, so drop it before encoding
+    let image = if transparent {
+        image
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    let mut smallest = usize::MAX;
+    for &dimension in DIMENSION_STEPS {
+        let resized;
+        let scaled = if image.width() > dimension || image.height() > dimension {
+            resized = image.thumbnail(dimension, dimension);
+            &resized
+        } else {
+            &image
+        };
+
+        if transparent {
+            let data = write(scaled, ImageOutputFormat::Png)?;
+            if data.len() <= budget {
+                return Ok(EncodedImage {
+                    data,
+                    filename: attachment_name(path, "png"),
+                });
+            }
+            smallest = smallest.min(data.len());
+            continue;
+        }
+
+        for &quality in QUALITY_STEPS {
+            let data = write(scaled, ImageOutputFormat::Jpeg(quality))?;
+            if data.len() <= budget {
+                debug!(
+                    "Encoded {:?} at {}px and quality {}",
+                    path, dimension, quality
+                );
+                return Ok(EncodedImage {
+                    data,
+                    filename: attachment_name(path, "jpg"),
+                });
+            }
+            smallest = smallest.min(data.len());
+        }
+    }
+
+    Err(EncodeError::TooLarge(smallest))
+}
+
+/// Name the encoded image of `path` is sent with
+pub fn attachment_name(path: &Path, extension: &str) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_owned());
+
+    format!("{}.{}", stem, extension)
+}
+
+/// Encodes all images in parallel on the blocking thread pool, keeping their order.
+/// Goes through `cache` when it's given.
+///
+/// Every image holds a permit from `limiter` while it's being processed,
+/// so the total amount of work is bounded no matter how many commands are running.
+pub async fn encode_all(
+    paths: Vec<PathBuf>,
+    budget: usize,
+    limiter: Arc<Semaphore>,
+    cache: Option<Arc<ThumbnailCache>>,
+) -> Vec<Result<EncodedImage, EncodeError>> {
+    let handles = paths
+        .into_iter()
+        .map(|path| {
+            let limiter = limiter.clone();
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire_owned().await;
+                task::spawn_blocking(move || match cache {
+                    Some(cache) => cache.encode(&path, budget),
+                    None => encode(&path, budget),
+                })
+                .await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        let result = match handle.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) | Err(e) => {
+                error!("Image encoding task failed: {}", e);
+                Err(EncodeError::Task(e))
+            }
+        };
+        results.push(result);
+    }
+
+    results
+}
+
+fn write(image: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, EncodeError> {
+    let mut buffer = Cursor::new(Vec::new());
+    image
+        .write_to(&mut buffer, format)
+        .map_err(EncodeError::Encode)?;
+    Ok(buffer.into_inner())
+}
+
+fn is_gif(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("gif"))
+        .unwrap_or(false)
+}
+
+fn is_animated_gif(path: &Path) -> Result<bool, EncodeError> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+    Ok(decoder.into_frames().take(2).count() > 1)
+}
+
+fn has_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+    image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("taribot-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn transparent_png_stays_png() {
+        let path = temp_file("transparent.png");
+        RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 0]))
+            .save(&path)
+            .unwrap();
+
+        let encoded = encode(&path, usize::MAX).unwrap();
+        assert!(encoded.filename.ends_with(".png"));
+        assert_eq!(
+            image::guess_format(&encoded.data).unwrap(),
+            image::ImageFormat::Png
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn opaque_png_becomes_jpeg() {
+        let path = temp_file("opaque.png");
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+
+        let encoded = encode(&path, usize::MAX).unwrap();
+        assert!(encoded.filename.ends_with(".jpg"));
+        assert_eq!(
+            image::guess_format(&encoded.data).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn steps_down_to_fit_budget() {
+        let path = temp_file("noise.png");
+        // Noise compresses badly so the first steps won't fit
+        RgbImage::from_fn(400, 400, |x, y| {
+            Rgb([(x * 31 + y * 17) as u8, (x * y) as u8, (x ^ y) as u8])
+        })
+        .save(&path)
+        .unwrap();
+
+        let full = encode(&path, usize::MAX).unwrap();
+        let budget = full.data.len() / 2;
+        let encoded = encode(&path, budget).unwrap();
+        assert!(encoded.data.len() <= budget);
+
+        assert!(matches!(encode(&path, 10), Err(EncodeError::TooLarge(_))));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let missing = temp_file("missing.jpg");
+        assert!(matches!(
+            encode(&missing, usize::MAX),
+            Err(EncodeError::Unreadable(_))
+        ));
+
+        let corrupt = temp_file("corrupt.jpg");
+        fs::write(&corrupt, b"definitely not a jpeg").unwrap();
+        assert!(matches!(
+            encode(&corrupt, usize::MAX),
+            Err(EncodeError::Decode(_))
+        ));
+
+        fs::remove_file(corrupt).unwrap();
+    }
+
+    #[tokio::test]
+    async fn encode_all_keeps_order() {
+        let first = temp_file("first.png");
+        let second = temp_file("second.png");
+        RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 0]))
+            .save(&first)
+            .unwrap();
+        RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+            .save(&second)
+            .unwrap();
+
+        let results = encode_all(
+            vec![first.clone(), temp_file("missing.png"), second.clone()],
+            usize::MAX,
+            Arc::new(Semaphore::new(1)),
+            None,
+        )
+        .await;
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].as_ref().unwrap().filename.ends_with(".png"));
+        assert!(results[1].is_err());
+        assert!(results[2].as_ref().unwrap().filename.ends_with(".jpg"));
+
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+}
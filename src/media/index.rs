@@ -0,0 +1,433 @@
+use rand::{seq::SliceRandom, thread_rng};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{debug, error, warn};
+
+/// In-memory listing of the media files in a directory.
+///
+/// Walking a directory with tens of thousands of files on every command is slow,
+/// so the listing is built once and refreshed periodically with [`spawn_rescan`].
+///
+/// Every top level subfolder is an album, files in nested folders belong to the album
+/// of their top level folder. Files directly in the root don't belong to any album.
+pub struct MediaIndex {
+    root: PathBuf,
+    extensions: &'static [&'static str],
+    /// Files larger than this many bytes are left out
+    max_size: Option<u64>,
+    library: RwLock<Library>,
+}
+
+#[derive(Default)]
+struct Library {
+    /// All files, grouped by album so every album is a continuous range
+    entries: Vec<PathBuf>,
+    /// Lowercase album name and its range in `entries`
+    albums: BTreeMap<String, Range<usize>>,
+}
+
+impl MediaIndex {
+    /// Creates the index and does the initial scan of `root`
+    #[cfg_attr(not(feature = "cat"), allow(dead_code))]
+    pub fn new(root: PathBuf, extensions: &'static [&'static str]) -> io::Result<Self> {
+        Self::build(root, extensions, None)
+    }
+
+    /// Same as [`MediaIndex::new`] but leaves out files larger than `max_size` bytes
+    #[cfg_attr(not(feature = "catvid"), allow(dead_code))]
+    pub fn with_max_size(
+        root: PathBuf,
+        extensions: &'static [&'static str],
+        max_size: u64,
+    ) -> io::Result<Self> {
+        Self::build(root, extensions, Some(max_size))
+    }
+
+    fn build(
+        root: PathBuf,
+        extensions: &'static [&'static str],
+        max_size: Option<u64>,
+    ) -> io::Result<Self> {
+        let index = MediaIndex {
+            root,
+            extensions,
+            max_size,
+            library: RwLock::new(Library::default()),
+        };
+        index.rescan()?;
+
+        Ok(index)
+    }
+
+    /// Reads the directory again and swaps in the new listing, returns the number of files found
+    pub fn rescan(&self) -> io::Result<usize> {
+        let mut scanner = Scanner {
+            extensions: self.extensions,
+            max_size: self.max_size,
+            too_large: 0,
+            visited: HashSet::new(),
+        };
+        let library = scanner.scan(&self.root)?;
+        if scanner.too_large > 0 {
+            warn!(
+                "Skipped {} files in {:?} that are over the {} byte limit",
+                scanner.too_large,
+                self.root,
+                self.max_size.unwrap_or_default()
+            );
+        }
+        let count = library.entries.len();
+        *self.library.write().unwrap() = library;
+
+        Ok(count)
+    }
+
+    /// Picks up to `count` random files without repeats, from the whole index if `album` is `None`
+    pub fn choose(&self, album: Option<&str>, count: usize) -> Vec<PathBuf> {
+        let library = self.library.read().unwrap();
+        library
+            .album(album)
+            .choose_multiple(&mut thread_rng(), count)
+            .cloned()
+            .collect()
+    }
+
+    /// Copy of every file in `album`, or in the whole index if `album` is `None`
+    pub fn entries(&self, album: Option<&str>) -> Vec<PathBuf> {
+        self.library.read().unwrap().album(album).to_vec()
+    }
+
+    pub fn has_album(&self, album: &str) -> bool {
+        self.library
+            .read()
+            .unwrap()
+            .albums
+            .contains_key(&album.to_lowercase())
+    }
+
+    /// Names of all albums that have at least one file in them
+    #[cfg_attr(not(feature = "cat"), allow(dead_code))]
+    pub fn albums(&self) -> Vec<String> {
+        self.library
+            .read()
+            .unwrap()
+            .albums
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.library.read().unwrap().entries.len()
+    }
+}
+
+impl Library {
+    /// Files in `album`, empty if it doesn't exist, or all files if `album` is `None`
+    fn album(&self, album: Option<&str>) -> &[PathBuf] {
+        match album {
+            Some(album) => match self.albums.get(&album.to_lowercase()) {
+                Some(range) => &self.entries[range.clone()],
+                None => &[],
+            },
+            None => &self.entries,
+        }
+    }
+}
+
+struct Scanner<'a> {
+    extensions: &'a [&'a str],
+    max_size: Option<u64>,
+    /// Number of files skipped for being over `max_size`
+    too_large: usize,
+    /// Canonical paths of the directories scanned so far, so symlinks can't loop
+    visited: HashSet<PathBuf>,
+}
+
+impl Scanner<'_> {
+    fn scan(&mut self, root: &Path) -> io::Result<Library> {
+        let mut library = Library::default();
+        self.visited.insert(root.canonicalize()?);
+
+        let mut albums = Vec::new();
+        for entry in root.read_dir()? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("Skipping an entry of {:?}: {}", root, e);
+                    continue;
+                }
+            };
+            if is_hidden(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => albums.push((name.to_lowercase(), path.clone())),
+                    None => debug!("Skipping album with non UTF-8 name: {:?}", path),
+                }
+            } else if self.accepts(&path) {
+                library.entries.push(path);
+            }
+        }
+
+        // Sorted so albums with names that only differ in case are next to each other
+        albums.sort();
+        for pair in albums.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                warn!(
+                    "Albums {:?} and {:?} only differ in case, merging them",
+                    pair[0].1, pair[1].1
+                );
+            }
+        }
+
+        for (name, album) in albums {
+            let start = library.entries.len();
+            self.scan_recursive(&album, &mut library.entries);
+            if library.entries.len() == start {
+                continue;
+            }
+            match library.albums.get_mut(&name) {
+                Some(range) => range.end = library.entries.len(),
+                None => {
+                    library.albums.insert(name, start..library.entries.len());
+                }
+            }
+        }
+
+        Ok(library)
+    }
+
+    /// Adds the accepted files in `dir` and its subdirectories to `entries`.
+    ///
+    /// Directories that can't be read are skipped so one bad folder doesn't hide everything else.
+    fn scan_recursive(&mut self, dir: &Path, entries: &mut Vec<PathBuf>) {
+        match dir.canonicalize() {
+            Ok(canonical) => {
+                if !self.visited.insert(canonical) {
+                    debug!("Skipping {:?}, it was already scanned", dir);
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Skipping {:?}: {}", dir, e);
+                return;
+            }
+        }
+
+        let read_dir = match dir.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                warn!("Skipping {:?}, failed to read it: {}", dir, e);
+                return;
+            }
+        };
+        for entry in read_dir {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("Skipping an entry of {:?}: {}", dir, e);
+                    continue;
+                }
+            };
+            if is_hidden(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                self.scan_recursive(&path, entries);
+            } else if self.accepts(&path) {
+                entries.push(path);
+            }
+        }
+    }
+
+    fn accepts(&mut self, path: &Path) -> bool {
+        if !has_extension(path, self.extensions) {
+            return false;
+        }
+
+        if let Some(max_size) = self.max_size {
+            match path.metadata() {
+                Ok(metadata) if metadata.len() <= max_size => (),
+                Ok(_) => {
+                    debug!("Skipping {:?}, it's over the size limit", path);
+                    self.too_large += 1;
+                    return false;
+                }
+                Err(e) => {
+                    debug!("Skipping {:?}, failed to read metadata: {}", path, e);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(false)
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Keeps the index up to date by rescanning it every `interval`, until the index is dropped
+pub fn spawn_rescan(index: Arc<MediaIndex>, interval: Duration) {
+    let index = Arc::downgrade(&index);
+
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        // First tick completes immediately and the index was just built
+        timer.tick().await;
+
+        loop {
+            timer.tick().await;
+
+            let index = match index.upgrade() {
+                Some(index) => index,
+                None => break,
+            };
+            match tokio::task::spawn_blocking(move || index.rescan()).await {
+                Ok(Ok(count)) => debug!("Rescanned media index, {} files", count),
+                Ok(Err(e)) => error!("Failed to rescan media index: {}", e),
+                Err(e) => error!("Media index rescan task failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("taribot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn scan_filters_extensions() {
+        let dir = temp_dir("index-filter");
+        fs::write(dir.join("a.jpg"), b"").unwrap();
+        fs::write(dir.join("b.JPEG"), b"").unwrap();
+        fs::write(dir.join("c.txt"), b"").unwrap();
+        fs::write(dir.join("noext"), b"").unwrap();
+
+        let index = MediaIndex::new(dir.clone(), &["jpg", "jpeg"]).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.choose(None, 5).len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rescan_picks_up_changes() {
+        let dir = temp_dir("index-rescan");
+        fs::write(dir.join("a.jpg"), b"").unwrap();
+
+        let index = MediaIndex::new(dir.clone(), &["jpg"]).unwrap();
+        assert_eq!(index.len(), 1);
+
+        fs::write(dir.join("b.jpg"), b"").unwrap();
+        fs::remove_file(dir.join("a.jpg")).unwrap();
+        assert_eq!(index.rescan().unwrap(), 1);
+        assert_eq!(index.choose(None, 1), vec![dir.join("b.jpg")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn subfolders_are_albums() {
+        let dir = temp_dir("index-albums");
+        fs::create_dir_all(dir.join("Mittens/2020")).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        fs::write(dir.join("root.jpg"), b"").unwrap();
+        fs::write(dir.join("Mittens/a.jpg"), b"").unwrap();
+        fs::write(dir.join("Mittens/2020/b.jpg"), b"").unwrap();
+        fs::write(dir.join(".hidden/c.jpg"), b"").unwrap();
+
+        let index = MediaIndex::new(dir.clone(), &["jpg"]).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.albums(), vec!["mittens".to_string()]);
+        assert!(index.has_album("MITTENS"));
+        assert!(!index.has_album("empty"));
+
+        let mut picked = index.choose(Some("mittens"), 5);
+        picked.sort();
+        assert_eq!(
+            picked,
+            vec![dir.join("Mittens/2020/b.jpg"), dir.join("Mittens/a.jpg")]
+        );
+        assert!(index.choose(Some("unknown"), 5).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_files_over_max_size() {
+        let dir = temp_dir("index-size");
+        fs::write(dir.join("small.mp4"), [0; 10]).unwrap();
+        fs::write(dir.join("large.mp4"), [0; 100]).unwrap();
+
+        let index = MediaIndex::with_max_size(dir.clone(), &["mp4"], 50).unwrap();
+        assert_eq!(index.entries(None), vec![dir.join("small.mp4")]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merges_albums_differing_in_case() {
+        let dir = temp_dir("index-case");
+        fs::create_dir_all(dir.join("Mittens")).unwrap();
+        fs::create_dir_all(dir.join("mittens")).unwrap();
+        fs::create_dir_all(dir.join("Tom")).unwrap();
+        fs::write(dir.join("Mittens/a.jpg"), b"").unwrap();
+        fs::write(dir.join("mittens/b.jpg"), b"").unwrap();
+        fs::write(dir.join("Tom/c.jpg"), b"").unwrap();
+
+        let index = MediaIndex::new(dir.clone(), &["jpg"]).unwrap();
+        assert_eq!(
+            index.albums(),
+            vec!["mittens".to_string(), "tom".to_string()]
+        );
+        assert_eq!(index.choose(Some("mittens"), 5).len(), 2);
+        assert_eq!(index.choose(Some("tom"), 5).len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_at_symlink_loops() {
+        let dir = temp_dir("index-symlink");
+        fs::create_dir_all(dir.join("Mittens/nested")).unwrap();
+        fs::write(dir.join("Mittens/nested/a.jpg"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.join("Mittens"), dir.join("Mittens/nested/loop")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("root")).unwrap();
+
+        let index = MediaIndex::new(dir.clone(), &["jpg"]).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.albums(), vec!["mittens".to_string()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
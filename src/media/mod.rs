@@ -0,0 +1,9 @@
+#[cfg(feature = "cat")]
+pub mod cache;
+#[cfg(feature = "cat")]
+pub mod encode;
+#[cfg(any(feature = "cat", feature = "catvid"))]
+pub mod index;
+#[cfg(feature = "cat")]
+pub mod pack;
+pub mod shuffle;
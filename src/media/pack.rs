@@ -0,0 +1,68 @@
+use super::encode::EncodedImage;
+
+/// Max number of attachments Discord allows in a single message
+const MAX_ATTACHMENTS: usize = 10;
+
+/// Splits the images into messages so the total size of every message stays under `budget`.
+///
+/// Images keep their order, a new message is started whenever the next image doesn't fit.
+/// Every single image is expected to be smaller than the budget already.
+pub fn pack(images: Vec<EncodedImage>, budget: usize) -> Vec<Vec<EncodedImage>> {
+    let mut messages = Vec::new();
+    let mut current: Vec<EncodedImage> = Vec::new();
+    let mut current_size = 0;
+
+    for image in images {
+        let size = image.data.len();
+        if !current.is_empty() && (current_size + size > budget || current.len() >= MAX_ATTACHMENTS)
+        {
+            messages.push(std::mem::take(&mut current));
+            current_size = 0;
+        }
+        current_size += size;
+        current.push(image);
+    }
+
+    if !current.is_empty() {
+        messages.push(current);
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: usize) -> EncodedImage {
+        EncodedImage {
+            data: vec![0; size],
+            filename: format!("{}.jpg", size),
+        }
+    }
+
+    fn sizes(messages: &[Vec<EncodedImage>]) -> Vec<Vec<usize>> {
+        messages
+            .iter()
+            .map(|message| message.iter().map(|image| image.data.len()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_budget() {
+        let messages = pack(vec![image(4), image(5), image(2), image(8)], 10);
+        assert_eq!(sizes(&messages), vec![vec![4, 5], vec![2, 8]]);
+    }
+
+    #[test]
+    fn splits_on_attachment_count() {
+        let messages = pack((0..12).map(|_| image(1)).collect(), 100);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].len(), MAX_ATTACHMENTS);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(pack(Vec::new(), 10).is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::storage::{Storage, StorageError};
+
+use rand::{seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    model::id::{ChannelId, GuildId},
+    prelude::TypeMapKey,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task;
+use tracing::{debug, error, warn};
+
+/// Who shares a shuffle bag
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShuffleScope {
+    /// Pick uniformly at random every time
+    Off,
+    Channel,
+    /// Direct messages fall back to the channel since they have no guild
+    Guild,
+}
+
+impl FromStr for ShuffleScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ShuffleScope::Off),
+            "channel" => Ok(ShuffleScope::Channel),
+            "guild" => Ok(ShuffleScope::Guild),
+            _ => Err(format!("Unknown shuffle scope {}", s)),
+        }
+    }
+}
+
+/// Items of a library drawn in the current round of a bag.
+///
+/// Only the hashes of the items are kept, which keeps the state small and lets
+/// the library change without starting the round over.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Bag {
+    drawn: HashSet<u64>,
+    /// Whether it changed since it was last saved
+    #[serde(skip)]
+    changed: bool,
+}
+
+/// Non-repeating random selection.
+///
+/// Every bag draws the items of a library that it hasn't drawn yet in the current round,
+/// so everything is shown once before anything repeats. Items added to the library join
+/// the current round and removed ones are left out, a new round starts when none are left.
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+pub struct Shuffler {
+    scope: ShuffleScope,
+    storage: Arc<Storage>,
+    bags: Mutex<HashMap<String, Bag>>,
+}
+
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+impl Shuffler {
+    /// Creates the shuffler and loads the bags saved to `storage`
+    pub fn new(scope: ShuffleScope, storage: Arc<Storage>) -> Result<Self, StorageError> {
+        let mut bags = HashMap::new();
+        for (key, state) in storage.shuffle_bags()? {
+            match serde_json::from_str(&state) {
+                Ok(bag) => {
+                    bags.insert(key, bag);
+                }
+                Err(e) => warn!("Dropping unreadable shuffle bag {}: {}", key, e),
+            }
+        }
+
+        Ok(Shuffler {
+            scope,
+            storage,
+            bags: Mutex::new(bags),
+        })
+    }
+
+    /// Key of the bag to use for `library` in the given channel, `None` when shuffling is off
+    pub fn key(
+        &self,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+        library: &str,
+    ) -> Option<String> {
+        match (self.scope, guild_id) {
+            (ShuffleScope::Off, _) => None,
+            (ShuffleScope::Guild, Some(guild_id)) => {
+                Some(format!("guild:{}:{}", guild_id, library))
+            }
+            _ => Some(format!("channel:{}:{}", channel_id, library)),
+        }
+    }
+
+    /// Draws up to `count` different items from the bag.
+    ///
+    /// `library` lists the items to draw from, and items that are no longer valid
+    /// according to `is_valid` are skipped. This goes through the whole library,
+    /// so use [`Shuffler::draw_blocking`] from async code.
+    pub fn draw<L, V>(&self, key: &str, count: usize, library: L, is_valid: V) -> Vec<String>
+    where
+        L: FnOnce() -> Vec<String>,
+        V: Fn(&str) -> bool,
+    {
+        let items = library();
+        let hashes = items.iter().map(|item| hash(item)).collect::<Vec<_>>();
+
+        let mut bags = self.bags.lock().unwrap();
+        let bag = bags.entry(key.to_owned()).or_default();
+        bag.changed = true;
+
+        let mut remaining = undrawn(&hashes, &bag.drawn);
+        let mut drawn = Vec::with_capacity(count);
+        let mut refilled = false;
+
+        while drawn.len() < count {
+            match remaining.pop() {
+                Some(index) => {
+                    // The library can have the same item more than once
+                    if bag.drawn.insert(hashes[index]) && is_valid(&items[index]) {
+                        drawn.push(items[index].clone());
+                    }
+                }
+                // Refill at most once per draw so an empty or invalid library can't loop forever
+                None if !refilled => {
+                    debug!("Starting a new round of shuffle bag {}", key);
+                    bag.drawn = drawn.iter().map(|item| hash(item)).collect();
+                    remaining = undrawn(&hashes, &bag.drawn);
+                    refilled = true;
+                }
+                None => break,
+            }
+        }
+
+        drawn
+    }
+
+    /// Same as [`Shuffler::draw`], on the blocking pool
+    pub async fn draw_blocking<L, V>(
+        self: Arc<Self>,
+        key: String,
+        count: usize,
+        library: L,
+        is_valid: V,
+    ) -> Vec<String>
+    where
+        L: FnOnce() -> Vec<String> + Send + 'static,
+        V: Fn(&str) -> bool + Send + 'static,
+    {
+        match task::spawn_blocking(move || self.draw(&key, count, library, is_valid)).await {
+            Ok(drawn) => drawn,
+            Err(e) => {
+                error!("Shuffle bag draw failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Writes the bags that changed since the last save to the database
+    pub fn save(&self) -> Result<(), StorageError> {
+        // Saved from a copy so drawing isn't blocked meanwhile
+        let changed = self
+            .bags
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, bag)| bag.changed)
+            .map(|(key, bag)| {
+                bag.changed = false;
+                (key.clone(), bag.clone())
+            })
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let states = changed
+            .iter()
+            .map(|(key, bag)| (key.clone(), serde_json::to_string(bag).unwrap()))
+            .collect::<Vec<_>>();
+        let saved = self.storage.save_shuffle_bags(&states);
+        if saved.is_err() {
+            // Try again on the next save
+            let mut bags = self.bags.lock().unwrap();
+            for (key, _) in changed {
+                if let Some(bag) = bags.get_mut(&key) {
+                    bag.changed = true;
+                }
+            }
+        }
+        saved
+    }
+}
+
+/// Indexes of the items whose hashes aren't in `drawn`, in random order
+fn undrawn(hashes: &[u64], drawn: &HashSet<u64>) -> Vec<usize> {
+    let mut undrawn = (0..hashes.len())
+        .filter(|&index| !drawn.contains(&hashes[index]))
+        .collect::<Vec<_>>();
+    undrawn.shuffle(&mut thread_rng());
+    undrawn
+}
+
+/// Identifies an item in a bag.
+///
+/// The std hasher can change between Rust releases, which at worst makes the bags
+/// repeat what they drew in their current round once.
+fn hash(item: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TypeMapKey for Shuffler {
+    type Value = Arc<Shuffler>;
+}
+
+/// Saves the bags every `interval`
+pub fn spawn_flush(shuffler: Arc<Shuffler>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        loop {
+            timer.tick().await;
+
+            let shuffler = shuffler.clone();
+            match task::spawn_blocking(move || shuffler.save()).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => error!("Failed to save shuffle state: {}", e),
+                Err(e) => error!("Shuffle state save task failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffler(scope: ShuffleScope) -> Shuffler {
+        Shuffler::new(scope, Arc::new(Storage::in_memory().unwrap())).unwrap()
+    }
+
+    fn library() -> Vec<String> {
+        (0..10).map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn goes_through_everything_before_repeating() {
+        let shuffler = shuffler(ShuffleScope::Channel);
+
+        let mut seen = HashSet::new();
+        for _ in 0..5 {
+            for item in shuffler.draw("key", 2, library, |_| true) {
+                assert!(seen.insert(item));
+            }
+        }
+        assert_eq!(seen.len(), 10);
+
+        // Bag is refilled after it runs out
+        assert_eq!(shuffler.draw("key", 3, library, |_| true).len(), 3);
+    }
+
+    #[test]
+    fn skips_invalid_items() {
+        let shuffler = shuffler(ShuffleScope::Channel);
+
+        let drawn = shuffler.draw("key", 10, library, |item| item != "3");
+        assert_eq!(drawn.len(), 9);
+        assert!(!drawn.contains(&"3".to_owned()));
+
+        assert!(shuffler.draw("empty", 1, Vec::new, |_| true).is_empty());
+    }
+
+    #[test]
+    fn keeps_round_when_library_changes() {
+        let shuffler = shuffler(ShuffleScope::Channel);
+
+        let first = shuffler.draw("key", 4, library, |_| true);
+        let grown = || (0..11).map(|i| i.to_string()).collect::<Vec<_>>();
+
+        // The new item is drawn along with the rest of the round
+        let mut rest = shuffler.draw("key", 7, grown, |_| true);
+        rest.sort();
+        let mut expected = grown()
+            .into_iter()
+            .filter(|item| !first.contains(item))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(rest, expected);
+    }
+
+    #[test]
+    fn keys() {
+        let channel = ChannelId(1);
+        let guild = Some(GuildId(2));
+
+        let off = shuffler(ShuffleScope::Off);
+        assert_eq!(off.key(guild, channel, "cat"), None);
+
+        let per_guild = shuffler(ShuffleScope::Guild);
+        assert_eq!(per_guild.key(guild, channel, "cat").unwrap(), "guild:2:cat");
+        assert_eq!(
+            per_guild.key(None, channel, "cat").unwrap(),
+            "channel:1:cat"
+        );
+    }
+
+    #[test]
+    fn persists_state() {
+        let storage = Arc::new(Storage::in_memory().unwrap());
+
+        let shuffler = Shuffler::new(ShuffleScope::Channel, storage.clone()).unwrap();
+        let first = shuffler.draw("key", 4, library, |_| true);
+        shuffler.save().unwrap();
+
+        let restored = Shuffler::new(ShuffleScope::Channel, storage).unwrap();
+        let rest = restored.draw("key", 6, library, |_| true);
+        assert!(rest.iter().all(|item| !first.contains(item)));
+    }
+}
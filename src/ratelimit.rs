@@ -0,0 +1,328 @@
+use serenity::{
+    framework::standard::{macros::check, Args, CommandOptions, Reason},
+    model::prelude::*,
+    prelude::*,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// At most `count` uses in `per`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub count: usize,
+    pub per: Duration,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parses `<count>/<seconds>`, e.g. `5/60` for 5 uses a minute
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid limit {}, expected <count>/<seconds>", s);
+
+        let (count, per) = s.split_once('/').ok_or_else(invalid)?;
+        let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+        let per = per.trim().parse::<u64>().map_err(|_| invalid())?;
+        if count < 1 || per < 1 {
+            return Err(format!(
+                "Count and seconds in limit {} have to be at least 1",
+                s
+            ));
+        }
+
+        Ok(Limit {
+            count,
+            per: Duration::from_secs(per),
+        })
+    }
+}
+
+/// Limits of a single command, no limit is applied where it's `None`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    pub user: Option<Limit>,
+    pub channel: Option<Limit>,
+    pub guild: Option<Limit>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Scope {
+    User,
+    Channel,
+    Guild,
+}
+
+/// The command was used too much, it can be used again after the duration
+#[derive(Debug, PartialEq)]
+pub struct Limited(pub Duration);
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Round up so it never says 0s
+        let secs = self.0.as_secs() + u64::from(self.0.subsec_nanos() > 0);
+        write!(f, "Slow down, try again in {}s", secs)
+    }
+}
+
+/// Sliding window limits on how often commands can be used by a user, in a channel and in a guild.
+///
+/// Owners aren't limited.
+pub struct RateLimiter {
+    owners: HashSet<UserId>,
+    /// Limits by command name
+    limits: RwLock<HashMap<String, RateLimits>>,
+    /// When the uses that are still inside their window happened, oldest first
+    hits: Mutex<HashMap<(String, Scope, u64), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(owners: HashSet<UserId>, limits: HashMap<String, RateLimits>) -> Self {
+        RateLimiter {
+            owners,
+            limits: RwLock::new(limits),
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the limits, uses that already happened still count towards the new ones
+    pub fn set_limits(&self, limits: HashMap<String, RateLimits>) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Records a use of `command`, or returns how long to wait if any of the limits is reached
+    pub fn hit(
+        &self,
+        command: &str,
+        user_id: UserId,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+    ) -> Result<(), Limited> {
+        if self.owners.contains(&user_id) {
+            return Ok(());
+        }
+        self.hit_at(command, user_id, channel_id, guild_id, Instant::now())
+    }
+
+    fn hit_at(
+        &self,
+        command: &str,
+        user_id: UserId,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        now: Instant,
+    ) -> Result<(), Limited> {
+        let limits = match self.limits.read().unwrap().get(command) {
+            Some(limits) => limits.clone(),
+            None => return Ok(()),
+        };
+
+        let mut buckets = vec![
+            (Scope::User, user_id.0, limits.user),
+            (Scope::Channel, channel_id.0, limits.channel),
+        ];
+        if let Some(guild_id) = guild_id {
+            buckets.push((Scope::Guild, guild_id.0, limits.guild));
+        }
+
+        let mut hits = self.hits.lock().unwrap();
+        let longest = [limits.user, limits.channel, limits.guild]
+            .iter()
+            .flatten()
+            .map(|limit| limit.per)
+            .max()
+            .unwrap_or_default();
+        // Forget the uses that can't count towards any limit anymore
+        hits.retain(|(name, _, _), times| {
+            name != command
+                || times
+                    .back()
+                    .is_some_and(|last| now.duration_since(*last) < longest)
+        });
+
+        // Check every bucket before recording anything so a rejected use doesn't count
+        let mut wait = Duration::ZERO;
+        for (scope, id, limit) in &buckets {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+            let key = (command.to_owned(), *scope, *id);
+            if let Some(times) = hits.get_mut(&key) {
+                while times
+                    .front()
+                    .is_some_and(|first| now.duration_since(*first) >= limit.per)
+                {
+                    times.pop_front();
+                }
+                if times.len() >= limit.count {
+                    // The oldest use has to leave the window before there's room again
+                    let first = times[times.len() - limit.count];
+                    wait = wait.max(limit.per - now.duration_since(first));
+                }
+            }
+        }
+        if !wait.is_zero() {
+            return Err(Limited(wait));
+        }
+
+        for (scope, id, limit) in buckets {
+            if limit.is_some() {
+                hits.entry((command.to_owned(), scope, id))
+                    .or_default()
+                    .push_back(now);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TypeMapKey for RateLimiter {
+    type Value = RateLimiter;
+}
+
+/// Records a use of `command`, the error is the message to show to the user
+pub async fn limit(
+    ctx: &Context,
+    command: &str,
+    user_id: UserId,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+) -> Result<(), String> {
+    let data = ctx.data.read().await;
+    match data.get::<RateLimiter>() {
+        Some(limiter) => limiter
+            .hit(command, user_id, channel_id, guild_id)
+            .map_err(|limited| limited.to_string()),
+        None => Ok(()),
+    }
+}
+
+#[check]
+#[name = "RateLimit"]
+// Help would use up the limit just by listing the command
+#[check_in_help(false)]
+#[display_in_help(false)]
+async fn rate_limit_check(
+    ctx: &Context,
+    msg: &Message,
+    _: &mut Args,
+    options: &CommandOptions,
+) -> Result<(), Reason> {
+    let command = options.names.first().copied().unwrap_or_default();
+
+    limit(ctx, command, msg.author.id, msg.channel_id, msg.guild_id)
+        .await
+        .map_err(Reason::User)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: RateLimits) -> RateLimiter {
+        RateLimiter::new(
+            HashSet::from([UserId(100)]),
+            HashMap::from([("cat".to_owned(), limits)]),
+        )
+    }
+
+    fn every(count: usize, secs: u64) -> Option<Limit> {
+        Some(Limit {
+            count,
+            per: Duration::from_secs(secs),
+        })
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!("5/60".parse(), Ok(every(5, 60).unwrap()));
+        assert!("5".parse::<Limit>().is_err());
+        assert!("0/60".parse::<Limit>().is_err());
+        assert!("five/60".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn limits_users_until_the_window_passes() {
+        let limiter = limiter(RateLimits {
+            user: every(2, 10),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let hit = |user, secs| limiter.hit_at("cat", UserId(user), ChannelId(1), None, at(secs));
+
+        assert!(hit(1, 0).is_ok());
+        assert!(hit(1, 4).is_ok());
+        assert_eq!(hit(1, 5), Err(Limited(Duration::from_secs(5))));
+        // Someone else isn't affected
+        assert!(hit(2, 5).is_ok());
+        assert!(hit(1, 10).is_ok());
+        // Other commands aren't limited
+        assert!(limiter
+            .hit_at("catvid", UserId(1), ChannelId(1), None, at(10))
+            .is_ok());
+    }
+
+    #[test]
+    fn rejected_uses_dont_count() {
+        let limiter = limiter(RateLimits {
+            user: every(5, 60),
+            channel: every(1, 10),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let hit = |user, secs| {
+            limiter.hit_at(
+                "cat",
+                UserId(user),
+                ChannelId(1),
+                Some(GuildId(1)),
+                start + Duration::from_secs(secs),
+            )
+        };
+
+        assert!(hit(1, 0).is_ok());
+        for secs in 1..10 {
+            assert!(hit(1, secs).is_err());
+        }
+        // Only the first use counts towards the user limit
+        for secs in 0..4 {
+            assert!(hit(1, 10 + secs * 10).is_ok());
+        }
+        assert!(hit(1, 50).is_err());
+    }
+
+    #[test]
+    fn owners_are_exempt() {
+        let limiter = limiter(RateLimits {
+            guild: every(1, 60),
+            ..Default::default()
+        });
+
+        for _ in 0..5 {
+            assert!(limiter
+                .hit("cat", UserId(100), ChannelId(1), Some(GuildId(1)))
+                .is_ok());
+        }
+        assert!(limiter
+            .hit("cat", UserId(1), ChannelId(1), Some(GuildId(1)))
+            .is_ok());
+        assert!(limiter
+            .hit("cat", UserId(2), ChannelId(2), Some(GuildId(1)))
+            .is_err());
+    }
+
+    #[test]
+    fn rounds_wait_up() {
+        assert_eq!(
+            Limited(Duration::from_millis(4200)).to_string(),
+            "Slow down, try again in 5s"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+#[cfg(feature = "cat")]
+use crate::commands::cat::CatConfig;
+#[cfg(feature = "catvid")]
+use crate::commands::catvid::CatvidConfig;
+use crate::commands::slash;
+use crate::config::BotConfig;
+use crate::ratelimit::RateLimiter;
+use crate::storage::Storage;
+#[cfg(feature = "catvid")]
+use crate::CatvidConfigContainer;
+
+use serenity::{http::Http, prelude::*};
+use std::sync::Arc;
+use tokio::task;
+use tracing::{info, warn};
+
+/// Command state that's built from the config and kept in the client data
+pub struct CommandConfigs {
+    #[cfg(feature = "cat")]
+    cat: Option<CatConfig>,
+    #[cfg(feature = "catvid")]
+    catvid: Option<CatvidConfig>,
+}
+
+impl CommandConfigs {
+    /// Builds the state of every enabled command, this scans the media folders so it blocks
+    #[cfg_attr(not(feature = "catvid"), allow(unused_variables))]
+    pub fn new(config: &BotConfig, storage: &Arc<Storage>) -> Result<Self, String> {
+        Ok(CommandConfigs {
+            #[cfg(feature = "cat")]
+            cat: config
+                .cat
+                .as_ref()
+                .map(|cat| CatConfig::new(cat, &config.guilds))
+                .transpose()?,
+            #[cfg(feature = "catvid")]
+            catvid: config
+                .catvid
+                .as_ref()
+                .map(|catvid| CatvidConfig::new(catvid, storage))
+                .transpose()?,
+        })
+    }
+
+    /// Replaces the old state in `data`
+    #[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(unused_variables))]
+    pub fn insert(self, data: &mut TypeMap) {
+        #[cfg(feature = "cat")]
+        if let Some(cat) = self.cat {
+            data.insert::<CatConfig>(cat);
+        }
+        #[cfg(feature = "catvid")]
+        if let Some(catvid) = self.catvid {
+            data.insert::<CatvidConfigContainer>(Arc::new(catvid));
+        }
+    }
+}
+
+/// Reads the config again and swaps it in, returns the changed settings that need a restart.
+///
+/// The new config is validated and everything is built from it before anything is replaced,
+/// so on an error the bot keeps running with the old config. The replaced folder indexes
+/// stop rescanning once the commands still using them are done. Slash commands are
+/// registered again since their limits come from the config.
+pub async fn reload(data: &RwLock<TypeMap>, http: &Http) -> Result<Vec<&'static str>, String> {
+    let config = task::spawn_blocking(BotConfig::reload)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let (old, storage) = {
+        let data = data.read().await;
+        let old = data.get::<BotConfig>().cloned();
+        let storage = data.get::<Storage>().cloned();
+        old.zip(storage)
+            .ok_or_else(|| "Failed to get BotConfig or Storage".to_string())?
+    };
+
+    // The command group is built on startup
+    #[cfg(feature = "cat")]
+    if old.cat.is_some() != config.cat.is_some() {
+        return Err("Enabling or disabling cat needs a restart".to_string());
+    }
+    #[cfg(feature = "catvid")]
+    if old.catvid.is_some() != config.catvid.is_some() {
+        return Err("Enabling or disabling catvid needs a restart".to_string());
+    }
+
+    let mut restart_needed = Vec::new();
+    if old.token != config.token {
+        restart_needed.push("token");
+    }
+    if old.database_path != config.database_path {
+        restart_needed.push("database_path");
+    }
+    if old.shuffle_scope != config.shuffle_scope {
+        restart_needed.push("shuffle_scope");
+    }
+    for setting in &restart_needed {
+        warn!("Changed {} won't be used until a restart", setting);
+    }
+
+    let config = Arc::new(config);
+    let commands = {
+        let config = config.clone();
+        task::spawn_blocking(move || CommandConfigs::new(&config, &storage))
+            .await
+            .map_err(|e| e.to_string())??
+    };
+
+    {
+        let mut data = data.write().await;
+        commands.insert(&mut data);
+        if let Some(limiter) = data.get::<RateLimiter>() {
+            limiter.set_limits(config.ratelimits.clone());
+        }
+        data.insert::<BotConfig>(config);
+    }
+    info!("Config reloaded");
+
+    if let Err(e) = slash::register(data, http).await {
+        warn!("Failed to register slash commands: {}", e);
+    }
+
+    Ok(restart_needed)
+}
+
+/// Reloads the config every time the process gets SIGHUP
+#[cfg(unix)]
+pub fn spawn_hangup_reload(data: Arc<RwLock<TypeMap>>, http: Arc<Http>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::error;
+
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Could not register SIGHUP handler: {}", e);
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            info!("Got SIGHUP, reloading config");
+            if let Err(e) = reload(&data, &http).await {
+                error!("Keeping the old config: {}", e);
+            }
+        }
+    });
+}
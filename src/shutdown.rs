@@ -0,0 +1,175 @@
+use crate::config::BotConfig;
+use crate::media::shuffle::Shuffler;
+
+use serenity::{client::bridge::gateway::ShardManager, prelude::*};
+use std::{sync::Arc, time::Duration};
+use tokio::{signal, sync::watch, task, time};
+use tracing::{error, info, warn};
+
+#[derive(Debug, Default)]
+struct State {
+    stopping: bool,
+    running: usize,
+}
+
+/// Keeps count of the commands that are running so shutting down can wait for them.
+///
+/// Once [`InFlight::drain`] is called no new commands are started.
+pub struct InFlight {
+    state: watch::Sender<State>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight {
+            state: watch::channel(State::default()).0,
+        }
+    }
+
+    /// Marks a command as running, returns `false` if it shouldn't run because the bot is stopping.
+    ///
+    /// Every `true` has to be followed by [`InFlight::end`].
+    pub fn begin(&self) -> bool {
+        let mut started = false;
+        self.state.send_modify(|state| {
+            if !state.stopping {
+                state.running += 1;
+                started = true;
+            }
+        });
+        started
+    }
+
+    pub fn end(&self) {
+        self.state
+            .send_modify(|state| state.running = state.running.saturating_sub(1));
+    }
+
+    pub fn running(&self) -> usize {
+        self.state.borrow().running
+    }
+
+    /// Stops new commands from starting and waits for the running ones to finish.
+    ///
+    /// Returns `false` if some were still running after `timeout`.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.state.send_modify(|state| state.stopping = true);
+
+        let mut state = self.state.subscribe();
+        let idle = async {
+            while state.borrow().running > 0 {
+                if state.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        time::timeout(timeout, idle).await.is_ok()
+    }
+}
+
+impl TypeMapKey for InFlight {
+    type Value = Arc<InFlight>;
+}
+
+/// Shuts the bot down once it gets SIGTERM or SIGINT.
+///
+/// Running commands get `shutdown_timeout` seconds to finish, then the shuffle state
+/// is saved and the shards are stopped. The database is written as things happen,
+/// so it has nothing left to save by then.
+pub fn spawn_signal_shutdown(data: Arc<RwLock<TypeMap>>, shard_manager: Arc<Mutex<ShardManager>>) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+
+        let (in_flight, shuffler, timeout) = {
+            let data = data.read().await;
+            (
+                data.get::<InFlight>().cloned(),
+                data.get::<Shuffler>().cloned(),
+                data.get::<BotConfig>()
+                    .map(|config| config.shutdown_timeout)
+                    .unwrap_or_default(),
+            )
+        };
+
+        if let Some(in_flight) = in_flight {
+            info!(
+                "Shutting down, waiting up to {}s for {} running commands",
+                timeout,
+                in_flight.running()
+            );
+            if !in_flight.drain(Duration::from_secs(timeout)).await {
+                warn!(
+                    "{} commands were still running after {}s, stopping anyway",
+                    in_flight.running(),
+                    timeout
+                );
+            }
+        }
+
+        if let Some(shuffler) = shuffler {
+            match task::spawn_blocking(move || shuffler.save()).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => error!("Failed to save shuffle state: {}", e),
+                Err(e) => error!("Shuffle state save task failed: {}", e),
+            }
+        }
+        shard_manager.lock().await.shutdown_all().await;
+    });
+}
+
+/// Waits for SIGTERM, which is what systemd and Docker stop with, or SIGINT (ctrl+c)
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = signal::ctrl_c() => info!("Got SIGINT"),
+                    _ = terminate.recv() => info!("Got SIGTERM"),
+                }
+                return;
+            }
+            Err(e) => warn!("Could not register SIGTERM handler: {}", e),
+        }
+    }
+
+    signal::ctrl_c()
+        .await
+        .expect("Could not register ctrl+c handler");
+    info!("Got SIGINT");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_running_commands() {
+        let in_flight = Arc::new(InFlight::new());
+        assert!(in_flight.begin());
+        assert!(in_flight.begin());
+
+        let finishing = in_flight.clone();
+        tokio::spawn(async move {
+            finishing.end();
+            time::sleep(Duration::from_millis(50)).await;
+            finishing.end();
+        });
+
+        assert!(in_flight.drain(Duration::from_secs(5)).await);
+        assert_eq!(in_flight.running(), 0);
+        // Nothing new starts once stopping
+        assert!(!in_flight.begin());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_timeout() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.begin());
+
+        assert!(!in_flight.drain(Duration::from_millis(50)).await);
+        assert_eq!(in_flight.running(), 1);
+    }
+}
@@ -0,0 +1,660 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::{
+    model::id::{ChannelId, GuildId, UserId},
+    prelude::{Context, TypeMapKey},
+};
+use std::{
+    borrow::Cow,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+use tracing::{error, info};
+
+pub use rusqlite::Error as StorageError;
+
+/// Schema changes in the order they're applied, the database remembers how many it has seen.
+///
+/// Never edit a migration that has been released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: settings, history, cache and shuffle bags
+    "CREATE TABLE guild_settings (
+        guild_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (guild_id, key)
+    );
+    CREATE TABLE command_history (
+        id INTEGER PRIMARY KEY,
+        command TEXT NOT NULL,
+        guild_id INTEGER,
+        channel_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        used_at INTEGER NOT NULL,
+        success INTEGER NOT NULL
+    );
+    CREATE INDEX command_history_used_at ON command_history (used_at);
+    CREATE TABLE cache (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        stored_at INTEGER NOT NULL
+    );
+    CREATE TABLE shuffle_bags (
+        key TEXT PRIMARY KEY,
+        state TEXT NOT NULL
+    );",
+    // 2: what cat and catvid sent, for the stats
+    "CREATE TABLE served (
+        id INTEGER PRIMARY KEY,
+        command TEXT NOT NULL,
+        guild_id INTEGER,
+        channel_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        served_at INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        bytes INTEGER NOT NULL
+    );
+    CREATE INDEX served_served_at ON served (served_at);
+    CREATE TABLE served_items (
+        served_id INTEGER NOT NULL REFERENCES served (id),
+        item TEXT NOT NULL
+    );
+    CREATE INDEX served_items_served_id ON served_items (served_id);",
+];
+
+/// Columns of [`Storage::export_served`]
+const EXPORT_HEADER: &str =
+    "id,command,guild_id,channel_id,user_id,served_at,latency_ms,bytes,items";
+
+/// A single use of a command
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandUse {
+    pub command: String,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub used_at: SystemTime,
+    pub success: bool,
+}
+
+/// What a single use of `cat` or `catvid` sent
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Served {
+    pub command: String,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub served_at: SystemTime,
+    /// From getting the command to sending the last message
+    pub latency: Duration,
+    /// Total size of the uploaded files, links don't count
+    pub bytes: u64,
+    /// Paths of the sent files and the sent links
+    pub items: Vec<String>,
+}
+
+/// Summary of what was served, most used first
+#[derive(Debug, Default, PartialEq)]
+pub struct UsageStats {
+    pub top_items: Vec<(String, u64)>,
+    pub top_users: Vec<(UserId, u64)>,
+    /// Uses by UTC date, oldest first. Days without any uses are left out.
+    pub per_day: Vec<(String, u64)>,
+}
+
+/// SQLite database for everything the bot has to remember between restarts.
+///
+/// Every call blocks on the database, so use `spawn_blocking` where it matters.
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating it if needed, and brings its schema up to date
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Database that's gone when the bot stops
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut connection: Connection) -> Result<Self, StorageError> {
+        migrate(&mut connection)?;
+
+        Ok(Storage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Value of the setting `key` in every guild that has it
+    pub fn guild_settings(&self, key: &str) -> Result<Vec<(GuildId, String)>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT guild_id, value FROM guild_settings WHERE key = ?1")?;
+        let rows = statement.query_map(params![key], |row| {
+            Ok((GuildId(row.get::<_, i64>(0)? as u64), row.get(1)?))
+        })?;
+
+        rows.collect()
+    }
+
+    /// Sets the setting `key` of the guild, `None` removes it
+    pub fn set_guild_setting(
+        &self,
+        guild_id: GuildId,
+        key: &str,
+        value: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        match value {
+            Some(value) => connection.execute(
+                "INSERT INTO guild_settings (guild_id, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (guild_id, key) DO UPDATE SET value = excluded.value",
+                params![guild_id.0 as i64, key, value],
+            )?,
+            None => connection.execute(
+                "DELETE FROM guild_settings WHERE guild_id = ?1 AND key = ?2",
+                params![guild_id.0 as i64, key],
+            )?,
+        };
+
+        Ok(())
+    }
+
+    pub fn record_command(&self, command: &CommandUse) -> Result<(), StorageError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO command_history (command, guild_id, channel_id, user_id, used_at, success)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                command.command,
+                command.guild_id.map(|guild_id| guild_id.0 as i64),
+                command.channel_id.0 as i64,
+                command.user_id.0 as i64,
+                unix_time(command.used_at),
+                command.success,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    #[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+    pub fn record_served(&self, served: &Served) -> Result<(), StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO served (command, guild_id, channel_id, user_id, served_at, latency_ms, bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                served.command,
+                served.guild_id.map(|guild_id| guild_id.0 as i64),
+                served.channel_id.0 as i64,
+                served.user_id.0 as i64,
+                unix_time(served.served_at),
+                served.latency.as_millis() as i64,
+                served.bytes as i64,
+            ],
+        )?;
+        let served_id = transaction.last_insert_rowid();
+        for item in &served.items {
+            transaction.execute(
+                "INSERT INTO served_items (served_id, item) VALUES (?1, ?2)",
+                params![served_id, item],
+            )?;
+        }
+
+        transaction.commit()
+    }
+
+    /// Stats of what was served since `since`, in `guild_id` or everywhere if it's `None`.
+    ///
+    /// The top lists have at most `top` entries.
+    pub fn usage_stats(
+        &self,
+        guild_id: Option<GuildId>,
+        since: SystemTime,
+        top: usize,
+    ) -> Result<UsageStats, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let filter = params![
+            unix_time(since),
+            guild_id.map(|guild_id| guild_id.0 as i64),
+            top as i64
+        ];
+
+        let top_items = connection
+            .prepare(
+                "SELECT item, COUNT(*) AS uses FROM served_items
+                 JOIN served ON served.id = served_items.served_id
+                 WHERE served_at >= ?1 AND (?2 IS NULL OR guild_id = ?2)
+                 GROUP BY item ORDER BY uses DESC, item LIMIT ?3",
+            )?
+            .query_map(filter, |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let top_users = connection
+            .prepare(
+                "SELECT user_id, COUNT(*) AS uses FROM served
+                 WHERE served_at >= ?1 AND (?2 IS NULL OR guild_id = ?2)
+                 GROUP BY user_id ORDER BY uses DESC, user_id LIMIT ?3",
+            )?
+            .query_map(filter, |row| {
+                Ok((
+                    UserId(row.get::<_, i64>(0)? as u64),
+                    row.get::<_, i64>(1)? as u64,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let per_day = connection
+            .prepare(
+                "SELECT date(served_at, 'unixepoch') AS day, COUNT(*) FROM served
+                 WHERE served_at >= ?1 AND (?2 IS NULL OR guild_id = ?2)
+                 GROUP BY day ORDER BY day",
+            )?
+            .query_map(&filter[..2], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(UsageStats {
+            top_items,
+            top_users,
+            per_day,
+        })
+    }
+
+    /// Everything that was served as CSV, one row per use with the items separated by `|`.
+    ///
+    /// `|` and `\` in the items are escaped with a `\`.
+    pub fn export_served(&self) -> Result<String, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, command, guild_id, channel_id, user_id,
+                 strftime('%Y-%m-%dT%H:%M:%SZ', served_at, 'unixepoch'), latency_ms, bytes,
+                 (SELECT group_concat(replace(replace(item, '\\', '\\\\'), '|', '\\|'), '|')
+                  FROM served_items WHERE served_id = served.id)
+             FROM served ORDER BY id",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut csv = format!("{}\n", EXPORT_HEADER);
+        while let Some(row) = rows.next()? {
+            let fields = [
+                row.get::<_, i64>(0)?.to_string(),
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<i64>>(2)?
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+                row.get::<_, i64>(3)?.to_string(),
+                row.get::<_, i64>(4)?.to_string(),
+                row.get::<_, String>(5)?,
+                row.get::<_, i64>(6)?.to_string(),
+                row.get::<_, i64>(7)?.to_string(),
+                row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+            ];
+            let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+
+        Ok(csv)
+    }
+
+    /// Cached value of `key` if it's younger than `max_age`, with its age
+    #[cfg_attr(not(feature = "catvid"), allow(dead_code))]
+    pub fn cached(
+        &self,
+        key: &str,
+        max_age: Duration,
+    ) -> Result<Option<(String, Duration)>, StorageError> {
+        let cached = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value, stored_at FROM cache WHERE key = ?1",
+                params![key],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()?;
+
+        Ok(cached.and_then(|(value, stored_at)| {
+            let age =
+                Duration::from_secs(unix_time(SystemTime::now()).saturating_sub(stored_at) as u64);
+            (age < max_age).then_some((value, age))
+        }))
+    }
+
+    #[cfg_attr(not(feature = "catvid"), allow(dead_code))]
+    pub fn cache(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO cache (key, value, stored_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value, stored_at = excluded.stored_at",
+            params![key, value, unix_time(SystemTime::now())],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every saved shuffle bag as its key and state
+    pub fn shuffle_bags(&self) -> Result<Vec<(String, String)>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT key, state FROM shuffle_bags")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        rows.collect()
+    }
+
+    /// Saves the states of `bags`, replacing the saved ones with the same keys
+    pub fn save_shuffle_bags(&self, bags: &[(String, String)]) -> Result<(), StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for (key, state) in bags {
+            transaction.execute(
+                "INSERT INTO shuffle_bags (key, state) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET state = excluded.state",
+                params![key, state],
+            )?;
+        }
+
+        transaction.commit()
+    }
+}
+
+impl TypeMapKey for Storage {
+    type Value = Arc<Storage>;
+}
+
+/// Adds the use to the command history, failures are only logged
+pub async fn record_use(ctx: &Context, used: CommandUse) {
+    let storage = match ctx.data.read().await.get::<Storage>() {
+        Some(storage) => storage.clone(),
+        None => return,
+    };
+
+    let command = used.command.clone();
+    match task::spawn_blocking(move || storage.record_command(&used)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => error!("Failed to record use of {}: {}", command, e),
+        Err(e) => error!("Failed to record use of {}: {}", command, e),
+    }
+}
+
+/// Adds what was sent to the stats, failures are only logged
+#[cfg_attr(not(any(feature = "cat", feature = "catvid")), allow(dead_code))]
+pub async fn record_served(ctx: &Context, served: Served) {
+    let storage = match ctx.data.read().await.get::<Storage>() {
+        Some(storage) => storage.clone(),
+        None => return,
+    };
+
+    let command = served.command.clone();
+    match task::spawn_blocking(move || storage.record_served(&served)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => error!("Failed to record what {} served: {}", command, e),
+        Err(e) => error!("Failed to record what {} served: {}", command, e),
+    }
+}
+
+/// Applies the migrations the database hasn't seen yet, each in its own transaction
+fn migrate(connection: &mut Connection) -> Result<(), StorageError> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        info!("Applied database migration {}", index + 1);
+    }
+
+    Ok(())
+}
+
+/// Seconds since the epoch, times before it are clamped to it
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Quotes `field` if it has anything that would break the CSV
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    /// Database file that's removed when dropped
+    struct TempDatabase(PathBuf);
+
+    impl TempDatabase {
+        fn new(name: &str) -> Self {
+            let path =
+                env::temp_dir().join(format!("taribot-{}-{}.sqlite", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            TempDatabase(path)
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn setting(storage: &Storage, guild_id: GuildId, key: &str) -> Option<String> {
+        storage
+            .guild_settings(key)
+            .unwrap()
+            .into_iter()
+            .find(|(id, _)| *id == guild_id)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn migrates_once() {
+        let database = TempDatabase::new("migrations");
+
+        let storage = Storage::open(&database.0).unwrap();
+        storage
+            .set_guild_setting(GuildId(1), "prefix", Some("!"))
+            .unwrap();
+        drop(storage);
+
+        // Opening it again must not run the migrations again or lose data
+        let storage = Storage::open(&database.0).unwrap();
+        let version: usize = storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(
+            setting(&storage, GuildId(1), "prefix").as_deref(),
+            Some("!")
+        );
+    }
+
+    #[test]
+    fn guild_settings() {
+        let database = TempDatabase::new("settings");
+        let storage = Storage::open(&database.0).unwrap();
+
+        storage
+            .set_guild_setting(GuildId(1), "prefix", Some("!"))
+            .unwrap();
+        storage
+            .set_guild_setting(GuildId(1), "prefix", Some("?"))
+            .unwrap();
+        storage
+            .set_guild_setting(GuildId(2), "prefix", Some("!"))
+            .unwrap();
+        storage
+            .set_guild_setting(GuildId(2), "other", Some("x"))
+            .unwrap();
+        storage
+            .set_guild_setting(GuildId(2), "prefix", None)
+            .unwrap();
+
+        assert_eq!(
+            storage.guild_settings("prefix").unwrap(),
+            vec![(GuildId(1), "?".to_owned())]
+        );
+        assert_eq!(setting(&storage, GuildId(2), "prefix"), None);
+        assert_eq!(setting(&storage, GuildId(2), "other").as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn records_commands() {
+        let database = TempDatabase::new("history");
+        let storage = Storage::open(&database.0).unwrap();
+
+        let used = CommandUse {
+            command: "cat".to_owned(),
+            guild_id: None,
+            channel_id: ChannelId(2),
+            user_id: UserId(3),
+            used_at: SystemTime::now(),
+            success: true,
+        };
+        storage.record_command(&used).unwrap();
+        storage
+            .record_command(&CommandUse {
+                guild_id: Some(GuildId(1)),
+                success: false,
+                ..used
+            })
+            .unwrap();
+
+        let count: i64 = storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM command_history WHERE command = 'cat' AND success = 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn cache_expires() {
+        let database = TempDatabase::new("cache");
+        let storage = Storage::open(&database.0).unwrap();
+
+        assert_eq!(
+            storage.cached("album", Duration::from_secs(60)).unwrap(),
+            None
+        );
+        storage.cache("album", "first").unwrap();
+        storage.cache("album", "second").unwrap();
+
+        let (value, age) = storage
+            .cached("album", Duration::from_secs(60))
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "second");
+        assert!(age < Duration::from_secs(60));
+        assert_eq!(storage.cached("album", Duration::ZERO).unwrap(), None);
+    }
+
+    fn served(user: u64, guild: Option<u64>, items: &[&str]) -> Served {
+        Served {
+            command: "cat".to_owned(),
+            guild_id: guild.map(GuildId),
+            channel_id: ChannelId(2),
+            user_id: UserId(user),
+            served_at: UNIX_EPOCH + Duration::from_secs(86400),
+            latency: Duration::from_millis(150),
+            bytes: 1024,
+            items: items.iter().map(|item| item.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn usage_stats() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .record_served(&served(1, Some(1), &["a.jpg", "b.jpg"]))
+            .unwrap();
+        storage
+            .record_served(&served(1, Some(1), &["a.jpg"]))
+            .unwrap();
+        storage
+            .record_served(&Served {
+                served_at: UNIX_EPOCH + Duration::from_secs(2 * 86400),
+                ..served(2, Some(1), &["c.jpg"])
+            })
+            .unwrap();
+        storage
+            .record_served(&served(3, Some(2), &["c.jpg"]))
+            .unwrap();
+
+        let stats = storage
+            .usage_stats(Some(GuildId(1)), UNIX_EPOCH, 2)
+            .unwrap();
+        assert_eq!(
+            stats.top_items,
+            vec![("a.jpg".to_owned(), 2), ("b.jpg".to_owned(), 1)]
+        );
+        assert_eq!(stats.top_users, vec![(UserId(1), 2), (UserId(2), 1)]);
+        assert_eq!(
+            stats.per_day,
+            vec![("1970-01-02".to_owned(), 2), ("1970-01-03".to_owned(), 1)]
+        );
+
+        let stats = storage
+            .usage_stats(None, UNIX_EPOCH + Duration::from_secs(2 * 86400), 5)
+            .unwrap();
+        assert_eq!(stats.top_items, vec![("c.jpg".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn exports_csv() {
+        let storage = Storage::in_memory().unwrap();
+        storage
+            .record_served(&served(1, None, &["a,b.jpg", "c.jpg"]))
+            .unwrap();
+        storage
+            .record_served(&served(1, None, &["a|b.jpg", "c\\d.jpg"]))
+            .unwrap();
+
+        let csv = storage.export_served().unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], EXPORT_HEADER);
+        assert_eq!(
+            lines[1],
+            "1,cat,,2,1,1970-01-02T00:00:00Z,150,1024,\"a,b.jpg|c.jpg\""
+        );
+        // Separators and backslashes in the items are escaped
+        assert_eq!(
+            lines[2],
+            "2,cat,,2,1,1970-01-02T00:00:00Z,150,1024,a\\|b.jpg|c\\\\d.jpg"
+        );
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("cat"), "cat");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
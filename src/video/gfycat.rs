@@ -0,0 +1,98 @@
+use super::{Video, VideoError, VideoSource};
+use crate::api::gfycat::{video_url, Client};
+use crate::storage::Storage;
+
+use serenity::async_trait;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task;
+use tracing::{debug, warn};
+
+/// Same as how long the client keeps the album contents
+const ALBUM_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Videos from a Gfycat album.
+///
+/// The album contents are kept in the storage so they survive restarts.
+pub struct GfycatSource {
+    client: Client,
+    storage: Arc<Storage>,
+    cache_key: String,
+    /// When the album contents that are in the storage were requested
+    saved: Mutex<Option<Instant>>,
+}
+
+impl GfycatSource {
+    /// Creates the source and restores the album contents from the storage, this blocks
+    pub fn new(client: Client, album_id: &str, storage: Arc<Storage>) -> Self {
+        let cache_key = format!("gfycat:album:{}", album_id);
+
+        match storage.cached(&cache_key, ALBUM_MAX_AGE) {
+            Ok(Some((json, age))) => match client.restore_album(&json, age) {
+                Ok(()) => debug!("Restored gfycat album {} from the storage", album_id),
+                Err(e) => warn!("Failed to restore gfycat album {}: {}", album_id, e),
+            },
+            Ok(None) => (),
+            Err(e) => warn!(
+                "Failed to read gfycat album {} from the storage: {}",
+                album_id, e
+            ),
+        }
+
+        GfycatSource {
+            saved: Mutex::new(client.album_requested_at()),
+            client,
+            storage,
+            cache_key,
+        }
+    }
+
+    /// Stores the album contents if the client requested them again.
+    ///
+    /// The write happens on the blocking pool so the command doesn't wait for it,
+    /// a failed write is only logged.
+    fn save_album(&self) {
+        let requested = self.client.album_requested_at();
+        {
+            let mut saved = self.saved.lock().unwrap();
+            if requested == *saved {
+                return;
+            }
+            *saved = requested;
+        }
+
+        if let Some(json) = self.client.album_json() {
+            let storage = self.storage.clone();
+            let cache_key = self.cache_key.clone();
+            task::spawn_blocking(move || {
+                if let Err(e) = storage.cache(&cache_key, &json) {
+                    warn!("Failed to store gfycat album: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl VideoSource for GfycatSource {
+    async fn videos(&self, nsfw: bool) -> Result<Vec<String>, VideoError> {
+        let ids = self.client.video_ids(nsfw).await?;
+        self.save_album();
+        Ok(ids)
+    }
+
+    async fn resolve(&self, id: &str) -> Result<Video, VideoError> {
+        let clip = self.client.clip(id).await?;
+        self.save_album();
+        // The video could have been removed from the album since the id was handed out
+        Ok(clip.map_or_else(|| Video::Url(video_url(id)), Video::Clip))
+    }
+
+    async fn random(&self, nsfw: bool) -> Result<Video, VideoError> {
+        let clip = self.client.random_clip(nsfw).await?;
+        self.save_album();
+        clip.map(Video::Clip).ok_or(VideoError::Empty)
+    }
+}
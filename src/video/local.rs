@@ -0,0 +1,60 @@
+use super::{Video, VideoError, VideoSource};
+use crate::media::index::MediaIndex;
+
+use rand::{seq::SliceRandom, thread_rng};
+use serenity::async_trait;
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "webm", "mov"];
+/// Album with the videos that are only sent to NSFW channels
+const NSFW_ALBUM: &str = "nsfw";
+
+/// Video files from a local directory that are uploaded as attachments.
+///
+/// The index is expected to leave out files that are over the upload limit.
+/// Videos in the top level `nsfw` folder are treated as NSFW.
+pub struct LocalSource {
+    videos: Arc<MediaIndex>,
+}
+
+impl LocalSource {
+    pub fn new(videos: Arc<MediaIndex>) -> Self {
+        LocalSource { videos }
+    }
+}
+
+#[async_trait]
+impl VideoSource for LocalSource {
+    async fn videos(&self, nsfw: bool) -> Result<Vec<String>, VideoError> {
+        let excluded: HashSet<PathBuf> = match nsfw {
+            true => HashSet::new(),
+            false => self.videos.entries(Some(NSFW_ALBUM)).into_iter().collect(),
+        };
+
+        Ok(self
+            .videos
+            .entries(None)
+            .into_iter()
+            .filter(|path| !excluded.contains(path))
+            .filter_map(|path| path.to_str().map(str::to_owned))
+            .collect())
+    }
+
+    async fn resolve(&self, id: &str) -> Result<Video, VideoError> {
+        Ok(Video::File(PathBuf::from(id)))
+    }
+
+    async fn random(&self, nsfw: bool) -> Result<Video, VideoError> {
+        if !nsfw && self.videos.has_album(NSFW_ALBUM) {
+            let videos = self.videos(nsfw).await?;
+            let id = videos.choose(&mut thread_rng()).ok_or(VideoError::Empty)?;
+            return self.resolve(id).await;
+        }
+
+        self.videos
+            .choose(None, 1)
+            .pop()
+            .map(Video::File)
+            .ok_or(VideoError::Empty)
+    }
+}
@@ -0,0 +1,163 @@
+pub mod gfycat;
+pub mod local;
+pub mod url_list;
+
+use crate::api::gfycat::RequestError;
+
+use rand::{seq::SliceRandom, thread_rng};
+use serenity::async_trait;
+use std::{error::Error, fmt, io, path::PathBuf};
+
+/// Video that's ready to be sent
+#[derive(Debug, PartialEq)]
+pub enum Video {
+    /// Link that Discord embeds by itself
+    Url(String),
+    /// File that's uploaded as an attachment
+    File(PathBuf),
+    /// Hosted clip with direct links to its media
+    Clip(Clip),
+}
+
+/// Video hosted somewhere else that has direct links to its media files
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    pub id: String,
+    pub title: Option<String>,
+    /// Page of the clip on the site hosting it
+    pub page_url: String,
+    pub poster_url: Option<String>,
+    /// Mp4 versions of the clip
+    pub renditions: Vec<Rendition>,
+}
+
+/// Single mp4 file of a clip
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendition {
+    pub url: String,
+    /// Size in bytes, if the host told it
+    pub size: Option<u64>,
+}
+
+impl Clip {
+    /// Renditions worth trying to upload with `limit`, best first.
+    ///
+    /// The largest ones that are known to fit come first, then the ones of unknown size,
+    /// which have to be checked while downloading.
+    pub fn upload_candidates(&self, limit: u64) -> Vec<&Rendition> {
+        let mut known = self
+            .renditions
+            .iter()
+            .filter(|rendition| rendition.size.is_some_and(|size| size <= limit))
+            .collect::<Vec<_>>();
+        known.sort_by_key(|rendition| std::cmp::Reverse(rendition.size));
+
+        known.extend(
+            self.renditions
+                .iter()
+                .filter(|rendition| rendition.size.is_none()),
+        );
+        known
+    }
+
+    /// Best quality direct link, for when nothing fits the upload limit
+    pub fn best_url(&self) -> Option<&str> {
+        self.renditions
+            .iter()
+            .max_by_key(|rendition| rendition.size)
+            .map(|rendition| rendition.url.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum VideoError {
+    Request(RequestError),
+    Io(io::Error),
+    /// Source has no videos
+    Empty,
+}
+
+impl Error for VideoError {}
+
+impl fmt::Display for VideoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VideoError::Request(error) => write!(f, "{}", error),
+            VideoError::Io(error) => write!(f, "{}", error),
+            VideoError::Empty => write!(f, "There are no videos"),
+        }
+    }
+}
+
+impl From<RequestError> for VideoError {
+    fn from(error: RequestError) -> Self {
+        Self::Request(error)
+    }
+}
+
+impl From<io::Error> for VideoError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Somewhere catvid can get videos from.
+///
+/// `nsfw` is whether videos flagged as NSFW can be included, sources without flags ignore it.
+#[async_trait]
+pub trait VideoSource: Send + Sync {
+    /// Ids of every available video, used for random picks and shuffle bags
+    async fn videos(&self, nsfw: bool) -> Result<Vec<String>, VideoError>;
+
+    /// Turns an id returned by [`VideoSource::videos`] into a video that can be sent
+    async fn resolve(&self, id: &str) -> Result<Video, VideoError>;
+
+    async fn random(&self, nsfw: bool) -> Result<Video, VideoError> {
+        let id = self
+            .videos(nsfw)
+            .await?
+            .choose(&mut thread_rng())
+            .cloned()
+            .ok_or(VideoError::Empty)?;
+
+        self.resolve(&id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendition(url: &str, size: Option<u64>) -> Rendition {
+        Rendition {
+            url: url.to_owned(),
+            size,
+        }
+    }
+
+    #[test]
+    fn picks_best_rendition_under_limit() {
+        let clip = Clip {
+            id: "Cat".to_owned(),
+            title: None,
+            page_url: "https://example.com/Cat".to_owned(),
+            poster_url: None,
+            renditions: vec![
+                rendition("mobile.mp4", None),
+                rendition("small.mp4", Some(1_000)),
+                rendition("full.mp4", Some(9_000)),
+                rendition("medium.mp4", Some(5_000)),
+            ],
+        };
+
+        let urls = |limit| {
+            clip.upload_candidates(limit)
+                .into_iter()
+                .map(|rendition| rendition.url.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(urls(8_000), vec!["medium.mp4", "small.mp4", "mobile.mp4"]);
+        assert_eq!(urls(500), vec!["mobile.mp4"]);
+        assert_eq!(clip.best_url(), Some("full.mp4"));
+    }
+}
@@ -0,0 +1,67 @@
+use super::{Video, VideoError, VideoSource};
+
+use serenity::async_trait;
+use std::path::PathBuf;
+
+/// Links listed in a text file, one per line.
+///
+/// Empty lines and lines starting with `#` are ignored. The file is read again
+/// on every request so it can be edited without restarting the bot.
+pub struct UrlListSource {
+    path: PathBuf,
+}
+
+impl UrlListSource {
+    pub fn new(path: PathBuf) -> Self {
+        UrlListSource { path }
+    }
+}
+
+#[async_trait]
+impl VideoSource for UrlListSource {
+    async fn videos(&self, _nsfw: bool) -> Result<Vec<String>, VideoError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+
+        Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    async fn resolve(&self, id: &str) -> Result<Video, VideoError> {
+        Ok(Video::Url(id.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn reads_urls() {
+        let path = std::env::temp_dir().join(format!("taribot-{}-urls.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# Cats\nhttps://example.com/a.mp4\n\n  https://example.com/b.mp4  \n",
+        )
+        .unwrap();
+
+        let source = UrlListSource::new(path.clone());
+        assert_eq!(
+            source.videos(false).await.unwrap(),
+            vec!["https://example.com/a.mp4", "https://example.com/b.mp4"]
+        );
+        assert!(matches!(
+            source.random(false).await.unwrap(),
+            Video::Url(url) if url.starts_with("https://example.com/")
+        ));
+
+        fs::write(&path, "# Nothing here\n").unwrap();
+        assert!(matches!(source.random(false).await, Err(VideoError::Empty)));
+
+        fs::remove_file(path).unwrap();
+    }
+}